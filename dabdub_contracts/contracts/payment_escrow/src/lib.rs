@@ -3,9 +3,76 @@
 mod test;
 
 use soroban_sdk::{
-    contract, contractevent, contractimpl, contracttype, token, Address, BytesN, Env, String,
+    contract, contracterror, contractevent, contractimpl, contracttype, token, xdr::ToXdr, Address,
+    Bytes, BytesN, Env, String, Vec,
 };
 
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum EscrowError {
+    NotAdmin = 1,
+    PaymentNotFound = 2,
+    PaymentAlreadyExists = 3,
+    InvalidAmount = 4,
+    InvalidTtl = 5,
+    TtlExceedsMaximum = 6,
+    PaymentFullyReleased = 7,
+    ReleaseExceedsRemaining = 8,
+    DisputeOpen = 9,
+    DisputeAlreadyOpen = 10,
+    DisputeNotOpen = 11,
+    DisputeWindowExpired = 12,
+    NotParticipant = 13,
+    InvalidDisputeWinner = 14,
+    PaymentNotPending = 15,
+    PaymentExpired = 16,
+    PaymentNotExpired = 17,
+    NoPendingAdmin = 18,
+    NotPendingAdmin = 19,
+    MissingRole = 20,
+    Paused = 21,
+    InvalidConfig = 22,
+    TokenNotAllowed = 23,
+    DepositBelowMinimum = 24,
+    DepositAboveMaximum = 25,
+    LockedTotalExceeded = 26,
+    InsufficientFees = 27,
+    FeeTierNotFound = 28,
+    RefundExceedsRemaining = 29,
+    AutoReleaseDisabled = 30,
+    DisputeWindowOpen = 31,
+    AutoReleasePending = 32,
+    InvalidMilestones = 33,
+    MilestoneNotFound = 34,
+    MilestoneNotPending = 35,
+    MilestonesActive = 36,
+    MilestoneDeadlineNotReached = 37,
+    InvalidStream = 38,
+    NotStream = 39,
+    StreamActive = 40,
+    NothingVested = 41,
+    InvalidTargetAmount = 42,
+    TargetExceeded = 43,
+    InvalidPayees = 44,
+    PaymentRequestNotFound = 45,
+    PaymentRequestExpired = 46,
+    InvalidFundingDeadline = 47,
+    MerchantKeyNotFound = 48,
+    QuoteExpired = 49,
+    QuoteAlreadyUsed = 50,
+    InvalidMetadata = 51,
+    ReferenceAlreadyUsed = 52,
+    InvalidSubscription = 53,
+    SubscriptionAlreadyExists = 54,
+    SubscriptionNotFound = 55,
+    SubscriptionInactive = 56,
+    SubscriptionComplete = 57,
+    ChargeNotDue = 58,
+    ReservedPaymentId = 59,
+    QuoteCustomerMismatch = 60,
+}
+
 #[contracttype]
 #[derive(Clone, Debug, PartialEq)]
 pub enum PaymentStatus {
@@ -13,11 +80,82 @@ pub enum PaymentStatus {
     Disputed,
     Released,
     Expired,
+    Refunded,
+    PartiallyRefunded,
+    Funded,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MilestoneStatus {
+    Pending,
+    Disputed,
+    Released,
+    Refunded,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MilestoneSpec {
+    pub amount: i128,
+    pub description_hash: BytesN<32>,
+    pub deadline: Option<u32>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Milestone {
+    pub amount: i128,
+    pub description_hash: BytesN<32>,
+    pub deadline: Option<u32>,
+    pub status: MilestoneStatus,
+    pub dispute_opened_by: Option<Address>,
+    pub dispute_bond: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Payee {
+    pub recipient: Address,
+    pub share_bps: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Payout {
+    pub recipient: Address,
+    pub amount: i128,
 }
 
 #[contracttype]
 #[derive(Clone, Debug)]
 pub struct PaymentEscrow {
+    pub payment_id: BytesN<32>,
+    pub token: Address,
+    pub amount: i128,
+    pub released_amount: i128,
+    pub refunded_amount: i128,
+    pub merchant: Address,
+    pub customer: Address,
+    pub payer: Address,
+    pub refund_to: Option<Address>,
+    pub status: PaymentStatus,
+    pub expiry: u32,
+    pub dispute_window_end: u32,
+    pub dispute_reason: Option<String>,
+    pub dispute_opened_by: Option<Address>,
+    pub dispute_bond: i128,
+    pub release_on_window_close: bool,
+    pub milestones: Vec<Milestone>,
+    pub created_ledger: u32,
+    pub stream_end_ledger: Option<u32>,
+    pub target_amount: Option<i128>,
+    pub payees: Vec<Payee>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct PaymentEscrowV1 {
     pub payment_id: BytesN<32>,
     pub amount: i128,
     pub released_amount: i128,
@@ -29,22 +167,283 @@ pub struct PaymentEscrow {
     pub dispute_reason: Option<String>,
 }
 
+impl PaymentEscrowV1 {
+    fn into_current(self, env: &Env, token: Address) -> PaymentEscrow {
+        PaymentEscrow {
+            payment_id: self.payment_id,
+            token,
+            amount: self.amount,
+            released_amount: self.released_amount,
+            refunded_amount: 0,
+            merchant: self.merchant,
+            customer: self.customer.clone(),
+            payer: self.customer,
+            refund_to: None,
+            status: self.status,
+            expiry: self.expiry,
+            dispute_window_end: self.dispute_window_end,
+            dispute_reason: self.dispute_reason,
+            dispute_opened_by: None,
+            dispute_bond: 0,
+            release_on_window_close: false,
+            milestones: Vec::new(env),
+            created_ledger: 0,
+            stream_end_ledger: None,
+            target_amount: None,
+            payees: Vec::new(env),
+        }
+    }
+}
+
+/// Per-payment settings chosen by the customer at deposit time. With
+/// `release_on_window_close` set, an undisputed payment belongs to the merchant
+/// once the dispute window ends and can no longer be refunded on expiry.
+/// Milestones, when given, must add up to the deposited amount. A stream
+/// vests the amount linearly from the deposit ledger over
+/// `stream_duration_ledgers`, which must end no later than expiry. With a
+/// `target_amount` the payment can be topped up until it is `Funded`. Payees
+/// split every merchant-side payout by `share_bps`, which must sum to 10,000.
+/// Customer-side payouts go to `refund_to` when set. `external_ref`,
+/// `order_hash` and `memo` are stored as the payment's metadata.
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DepositOptions {
+    pub ttl_ledgers: Option<u32>,
+    pub release_on_window_close: bool,
+    pub milestones: Option<Vec<MilestoneSpec>>,
+    pub stream_duration_ledgers: Option<u32>,
+    pub target_amount: Option<i128>,
+    pub payees: Option<Vec<Payee>>,
+    pub refund_to: Option<Address>,
+    pub external_ref: Option<String>,
+    pub order_hash: Option<BytesN<32>>,
+    pub memo: Option<String>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaymentMetadata {
+    pub external_ref: Option<String>,
+    pub order_hash: Option<BytesN<32>>,
+    pub memo: Option<String>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaymentRequest {
+    pub payment_id: BytesN<32>,
+    pub merchant: Address,
+    pub token: Address,
+    pub amount: i128,
+    pub funding_deadline: u32,
+}
+
+/// Checkout terms signed off-chain by the merchant. The signature covers the
+/// XDR of `(escrow contract address, quote)`; `expiry` is the last ledger the
+/// quote can be redeemed and each `nonce` is accepted once per merchant. A
+/// quote with a `customer` can only be redeemed by that address.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MerchantQuote {
+    pub payment_id: BytesN<32>,
+    pub merchant: Address,
+    pub amount: i128,
+    pub token: Address,
+    pub expiry: u32,
+    pub nonce: u64,
+    pub customer: Option<Address>,
+}
+
+/// Recurring plan agreed by the customer: every `period_ledgers` ledgers, up
+/// to `max_periods` times, `amount_per_period` of `token` is pulled from the
+/// customer's allowance into a new escrowed payment to `merchant`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubscriptionTerms {
+    pub merchant: Address,
+    pub token: Address,
+    pub amount_per_period: i128,
+    pub period_ledgers: u32,
+    pub max_periods: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Subscription {
+    pub subscription_id: BytesN<32>,
+    pub customer: Address,
+    pub merchant: Address,
+    pub token: Address,
+    pub amount_per_period: i128,
+    pub period_ledgers: u32,
+    pub max_periods: u32,
+    pub periods_charged: u32,
+    pub next_charge_ledger: u32,
+    pub active: bool,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    Releaser,
+    Arbiter,
+    Pauser,
+    FeeManager,
+    RoleAdmin,
+    FeeCollector,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PauseScope {
+    All,
+    Deposits,
+    Releases,
+    Refunds,
+    Disputes,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PauseState {
+    pub deposits: bool,
+    pub releases: bool,
+    pub refunds: bool,
+    pub disputes: bool,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EscrowConfig {
+    pub default_ttl_ledgers: u32,
+    pub max_ttl_ledgers: u32,
+    pub max_dispute_window_ledgers: u32,
+    pub dispute_bond_bps: u32,
+    pub forfeit_bond_to_fees: bool,
+    pub extension_requires_merchant: bool,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TokenLimits {
+    pub min_deposit: i128,
+    pub max_deposit: Option<i128>,
+    pub max_locked: Option<i128>,
+}
+
+/// Fee charged on every payout to a merchant: `amount * fee_bps / 10_000`,
+/// clamped to `[min_fee, max_fee]` and never more than the payout itself.
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FeeSchedule {
+    pub fee_bps: u32,
+    pub min_fee: i128,
+    pub max_fee: Option<i128>,
+}
+
 #[contracttype]
 #[derive(Clone)]
 pub enum DataKey {
     Admin,
+    PendingAdmin,
     UsdcToken,
     DefaultTtlLedgers,
     Payment(BytesN<32>),
+    Role(Role, Address),
+    PauseState,
+    SchemaVersion,
+    PaymentV2(BytesN<32>),
+    Config,
+    AllowedTokens,
+    TokenLimits(Address),
+    LockedTotal(Address),
+    FeeSchedule,
+    AccruedFees(Address),
+    FeeTier(u32),
+    MerchantFeeTier(Address),
+    MerchantFeeOverride(Address),
+    PaymentRequest(BytesN<32>),
+    MerchantSigningKey(Address),
+    QuoteNonce(Address, u64),
+    PaymentMetadata(BytesN<32>),
+    PaymentReference(String),
+    Subscription(BytesN<32>),
 }
 
 #[contractevent(topics = ["ESCROW", "deposit"])]
 struct DepositEvent {
     payment_id: BytesN<32>,
+    payer: Address,
     customer: Address,
     merchant: Address,
+    token: Address,
     amount: i128,
     expiry: u32,
+    metadata: Option<PaymentMetadata>,
+}
+
+#[contractevent(topics = ["ESCROW", "payment_request_created"])]
+struct PaymentRequestCreatedEvent {
+    payment_id: BytesN<32>,
+    merchant: Address,
+    token: Address,
+    amount: i128,
+    funding_deadline: u32,
+}
+
+#[contractevent(topics = ["ESCROW", "payment_request_funded"])]
+struct PaymentRequestFundedEvent {
+    payment_id: BytesN<32>,
+    customer: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "merchant_signing_key_updated"])]
+struct MerchantSigningKeyUpdatedEvent {
+    merchant: Address,
+    public_key: Option<BytesN<32>>,
+}
+
+#[contractevent(topics = ["ESCROW", "quote_redeemed"])]
+struct QuoteRedeemedEvent {
+    payment_id: BytesN<32>,
+    merchant: Address,
+    customer: Address,
+    nonce: u64,
+}
+
+#[contractevent(topics = ["ESCROW", "subscription_created"])]
+struct SubscriptionCreatedEvent {
+    subscription_id: BytesN<32>,
+    customer: Address,
+    merchant: Address,
+    token: Address,
+    amount_per_period: i128,
+    period_ledgers: u32,
+    max_periods: u32,
+}
+
+#[contractevent(topics = ["ESCROW", "subscription_charged"])]
+struct SubscriptionChargedEvent {
+    subscription_id: BytesN<32>,
+    payment_id: BytesN<32>,
+    period: u32,
+    amount: i128,
+    next_charge_ledger: u32,
+}
+
+#[contractevent(topics = ["ESCROW", "subscription_cancelled"])]
+struct SubscriptionCancelledEvent {
+    subscription_id: BytesN<32>,
+    customer: Address,
+    periods_charged: u32,
+}
+
+#[contractevent(topics = ["ESCROW", "top_up"])]
+struct TopUpEvent {
+    payment_id: BytesN<32>,
+    payer: Address,
+    amount: i128,
+    total: i128,
 }
 
 #[contractevent(topics = ["ESCROW", "release"])]
@@ -52,6 +451,8 @@ struct ReleaseEvent {
     payment_id: BytesN<32>,
     merchant: Address,
     amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
 }
 
 #[contractevent(topics = ["ESCROW", "partial_release"])]
@@ -59,13 +460,52 @@ struct PartialReleaseEvent {
     payment_id: BytesN<32>,
     merchant: Address,
     amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+}
+
+#[contractevent(topics = ["ESCROW", "delivery_confirmed"])]
+struct DeliveryConfirmedEvent {
+    payment_id: BytesN<32>,
+    customer: Address,
+    merchant: Address,
+    amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+}
+
+#[contractevent(topics = ["ESCROW", "window_close_release"])]
+struct WindowCloseReleaseEvent {
+    payment_id: BytesN<32>,
+    merchant: Address,
+    amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
 }
 
 #[contractevent(topics = ["ESCROW", "expiry"])]
 struct ExpiryEvent {
     payment_id: BytesN<32>,
     customer: Address,
+    recipient: Address,
+    amount: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "refund"])]
+struct RefundEvent {
+    payment_id: BytesN<32>,
+    customer: Address,
+    recipient: Address,
     amount: i128,
+    remaining: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "expiry_extended"])]
+struct ExpiryExtendedEvent {
+    payment_id: BytesN<32>,
+    previous_expiry: u32,
+    expiry: u32,
+    dispute_window_end: u32,
 }
 
 #[contractevent(topics = ["ESCROW", "dispute_opened"])]
@@ -73,72 +513,2061 @@ struct DisputeOpenedEvent {
     payment_id: BytesN<32>,
     opened_by: Address,
     reason: String,
+    bond: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "dispute_resolved"])]
+struct DisputeResolvedEvent {
+    payment_id: BytesN<32>,
+    winner: Address,
+    amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+    bond: i128,
+    bond_returned: bool,
+}
+
+#[contractevent(topics = ["ESCROW", "milestone_approved"])]
+struct MilestoneApprovedEvent {
+    payment_id: BytesN<32>,
+    index: u32,
+    approved_by: Address,
+    amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+}
+
+#[contractevent(topics = ["ESCROW", "milestone_refunded"])]
+struct MilestoneRefundedEvent {
+    payment_id: BytesN<32>,
+    index: u32,
+    customer: Address,
+    recipient: Address,
+    amount: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "milestone_dispute_opened"])]
+struct MilestoneDisputeOpenedEvent {
+    payment_id: BytesN<32>,
+    index: u32,
+    opened_by: Address,
+    reason: String,
+    bond: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "milestone_dispute_resolved"])]
+struct MilestoneDisputeResolvedEvent {
+    payment_id: BytesN<32>,
+    index: u32,
+    winner: Address,
+    amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+    bond: i128,
+    bond_returned: bool,
+}
+
+#[contractevent(topics = ["ESCROW", "vested_withdrawal"])]
+struct VestedWithdrawalEvent {
+    payment_id: BytesN<32>,
+    merchant: Address,
+    amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+    vested: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "stream_cancelled"])]
+struct StreamCancelledEvent {
+    payment_id: BytesN<32>,
+    customer: Address,
+    recipient: Address,
+    merchant_amount: i128,
+    fee: i128,
+    payouts: Vec<Payout>,
+    refunded: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "admin_proposed"])]
+struct AdminProposedEvent {
+    admin: Address,
+    pending_admin: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "admin_accepted"])]
+struct AdminAcceptedEvent {
+    previous_admin: Address,
+    admin: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "admin_proposal_cancelled"])]
+struct AdminProposalCancelledEvent {
+    admin: Address,
+    pending_admin: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "role_granted"])]
+struct RoleGrantedEvent {
+    role: Role,
+    account: Address,
+    sender: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "role_revoked"])]
+struct RoleRevokedEvent {
+    role: Role,
+    account: Address,
+    sender: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "paused"])]
+struct PausedEvent {
+    scope: PauseScope,
+    sender: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "unpaused"])]
+struct UnpausedEvent {
+    scope: PauseScope,
+    sender: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "upgraded"])]
+struct UpgradedEvent {
+    new_wasm_hash: BytesN<32>,
+}
+
+#[contractevent(topics = ["ESCROW", "migrated"])]
+struct MigratedEvent {
+    from_version: u32,
+    to_version: u32,
+}
+
+#[contractevent(topics = ["ESCROW", "default_ttl_updated"])]
+struct DefaultTtlUpdatedEvent {
+    previous_ttl_ledgers: u32,
+    default_ttl_ledgers: u32,
+}
+
+#[contractevent(topics = ["ESCROW", "config_updated"])]
+struct ConfigUpdatedEvent {
+    config: EscrowConfig,
+}
+
+#[contractevent(topics = ["ESCROW", "token_allowed"])]
+struct TokenAllowedEvent {
+    token: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "token_disallowed"])]
+struct TokenDisallowedEvent {
+    token: Address,
+}
+
+#[contractevent(topics = ["ESCROW", "token_limits_updated"])]
+struct TokenLimitsUpdatedEvent {
+    token: Address,
+    limits: TokenLimits,
+}
+
+#[contractevent(topics = ["ESCROW", "fee_schedule_updated"])]
+struct FeeScheduleUpdatedEvent {
+    schedule: FeeSchedule,
+}
+
+#[contractevent(topics = ["ESCROW", "fees_withdrawn"])]
+struct FeesWithdrawnEvent {
+    token: Address,
+    recipient: Address,
+    amount: i128,
+}
+
+#[contractevent(topics = ["ESCROW", "fee_tier_updated"])]
+struct FeeTierUpdatedEvent {
+    tier: u32,
+    multiplier_bps: Option<u32>,
 }
 
-#[contractevent(topics = ["ESCROW", "dispute_resolved"])]
-struct DisputeResolvedEvent {
-    payment_id: BytesN<32>,
-    winner: Address,
-    amount: i128,
-}
+#[contractevent(topics = ["ESCROW", "merchant_fee_tier_updated"])]
+struct MerchantFeeTierUpdatedEvent {
+    merchant: Address,
+    tier: Option<u32>,
+}
+
+#[contractevent(topics = ["ESCROW", "merchant_fee_override_updated"])]
+struct MerchantFeeOverrideUpdatedEvent {
+    merchant: Address,
+    schedule: Option<FeeSchedule>,
+}
+
+const BPS_DENOMINATOR: i128 = 10_000;
+const DEFAULT_FEE_MULTIPLIER_BPS: u32 = 10_000;
+const MAX_FEE_MULTIPLIER_BPS: u32 = 100_000;
+
+const SCHEMA_VERSION: u32 = 2;
+/// First schema version that stores roles; older deployments only had the
+/// single admin.
+const ROLES_SCHEMA_VERSION: u32 = 2;
+
+const MAX_MILESTONES: u32 = 20;
+const MAX_PAYEES: u32 = 10;
+const MAX_EXTERNAL_REF_LEN: u32 = 64;
+const MAX_MEMO_LEN: u32 = 256;
+/// Leading bytes of every subscription payment id; callers cannot pick ids
+/// with this prefix, so subscription charges can never collide with them.
+const SUBSCRIPTION_PAYMENT_PREFIX: [u8; 4] = *b"SUB:";
+
+const ALL_ROLES: [Role; 6] = [
+    Role::Releaser,
+    Role::Arbiter,
+    Role::Pauser,
+    Role::FeeManager,
+    Role::RoleAdmin,
+    Role::FeeCollector,
+];
+
+const DEFAULT_MAX_DISPUTE_WINDOW_LEDGERS: u32 = 51_840;
+const DEFAULT_MAX_TTL_LEDGERS: u32 = 518_400;
+
+#[contract]
+pub struct PaymentEscrowContract;
+
+#[contractimpl]
+impl PaymentEscrowContract {
+    pub fn __constructor(
+        env: Env,
+        admin: Address,
+        usdc_token: Address,
+        default_ttl_ledgers: u32,
+    ) -> Result<(), EscrowError> {
+        let config = EscrowConfig {
+            default_ttl_ledgers,
+            max_ttl_ledgers: DEFAULT_MAX_TTL_LEDGERS,
+            max_dispute_window_ledgers: DEFAULT_MAX_DISPUTE_WINDOW_LEDGERS,
+            dispute_bond_bps: 0,
+            forfeit_bond_to_fees: false,
+            extension_requires_merchant: false,
+        };
+        Self::validate_ttl(&config, default_ttl_ledgers)?;
+
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        env.storage()
+            .instance()
+            .set(&DataKey::UsdcToken, &usdc_token);
+        env.storage().instance().set(
+            &DataKey::AllowedTokens,
+            &Vec::from_array(&env, [usdc_token]),
+        );
+        env.storage().instance().set(&DataKey::Config, &config);
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        Self::grant_all_roles(&env, &admin);
+        Ok(())
+    }
+
+    pub fn deposit(
+        env: Env,
+        customer: Address,
+        payment_id: BytesN<32>,
+        merchant: Address,
+        token: Address,
+        amount: i128,
+        options: Option<DepositOptions>,
+    ) -> Result<BytesN<32>, EscrowError> {
+        customer.require_auth();
+        Self::require_caller_chosen_id(&payment_id)?;
+        Self::open_payment(
+            env.clone(),
+            customer.clone(),
+            customer.clone(),
+            payment_id.clone(),
+            merchant,
+            token.clone(),
+            amount,
+            options,
+        )?;
+        Self::transfer_to_contract(&env, &token, &customer, amount);
+        Ok(payment_id)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn deposit_for(
+        env: Env,
+        payer: Address,
+        customer: Address,
+        payment_id: BytesN<32>,
+        merchant: Address,
+        token: Address,
+        amount: i128,
+        options: Option<DepositOptions>,
+    ) -> Result<BytesN<32>, EscrowError> {
+        payer.require_auth();
+        Self::require_caller_chosen_id(&payment_id)?;
+        Self::open_payment(
+            env.clone(),
+            payer.clone(),
+            customer,
+            payment_id.clone(),
+            merchant,
+            token.clone(),
+            amount,
+            options,
+        )?;
+        Self::transfer_to_contract(&env, &token, &payer, amount);
+        Ok(payment_id)
+    }
+
+    pub fn create_payment_request(
+        env: Env,
+        merchant: Address,
+        payment_id: BytesN<32>,
+        amount: i128,
+        token: Address,
+        funding_deadline: u32,
+    ) -> Result<(), EscrowError> {
+        merchant.require_auth();
+        Self::require_not_paused(&env, PauseScope::Deposits)?;
+        Self::require_caller_chosen_id(&payment_id)?;
+
+        if amount <= 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+        if !Self::is_token_allowed(env.clone(), token.clone()) {
+            return Err(EscrowError::TokenNotAllowed);
+        }
+        if funding_deadline <= env.ledger().sequence() {
+            return Err(EscrowError::InvalidFundingDeadline);
+        }
+        if Self::payment_exists(&env, &payment_id) {
+            return Err(EscrowError::PaymentAlreadyExists);
+        }
+        // Only the merchant who issued a request may reissue it once expired.
+        if let Ok(existing) = Self::get_payment_request(env.clone(), payment_id.clone()) {
+            if existing.funding_deadline >= env.ledger().sequence() || existing.merchant != merchant
+            {
+                return Err(EscrowError::PaymentAlreadyExists);
+            }
+        }
+
+        let request = PaymentRequest {
+            payment_id: payment_id.clone(),
+            merchant: merchant.clone(),
+            token: token.clone(),
+            amount,
+            funding_deadline,
+        };
+        env.storage()
+            .persistent()
+            .set(&DataKey::PaymentRequest(payment_id.clone()), &request);
+
+        PaymentRequestCreatedEvent {
+            payment_id,
+            merchant,
+            token,
+            amount,
+            funding_deadline,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn fund_request(
+        env: Env,
+        customer: Address,
+        payment_id: BytesN<32>,
+    ) -> Result<BytesN<32>, EscrowError> {
+        customer.require_auth();
+
+        let request = Self::get_payment_request(env.clone(), payment_id.clone())?;
+        if env.ledger().sequence() > request.funding_deadline {
+            return Err(EscrowError::PaymentRequestExpired);
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::PaymentRequest(payment_id.clone()));
+
+        Self::open_payment(
+            env.clone(),
+            customer.clone(),
+            customer.clone(),
+            payment_id.clone(),
+            request.merchant,
+            request.token.clone(),
+            request.amount,
+            None,
+        )?;
+        Self::transfer_to_contract(&env, &request.token, &customer, request.amount);
+
+        PaymentRequestFundedEvent {
+            payment_id: payment_id.clone(),
+            customer,
+        }
+        .publish(&env);
+        Ok(payment_id)
+    }
+
+    pub fn set_merchant_signing_key(
+        env: Env,
+        merchant: Address,
+        public_key: BytesN<32>,
+    ) -> Result<(), EscrowError> {
+        merchant.require_auth();
+
+        env.storage()
+            .persistent()
+            .set(&DataKey::MerchantSigningKey(merchant.clone()), &public_key);
+
+        MerchantSigningKeyUpdatedEvent {
+            merchant,
+            public_key: Some(public_key),
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn clear_merchant_signing_key(env: Env, merchant: Address) -> Result<(), EscrowError> {
+        merchant.require_auth();
+
+        env.storage()
+            .persistent()
+            .remove(&DataKey::MerchantSigningKey(merchant.clone()));
+
+        MerchantSigningKeyUpdatedEvent {
+            merchant,
+            public_key: None,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn deposit_with_quote(
+        env: Env,
+        customer: Address,
+        quote: MerchantQuote,
+        signature: BytesN<64>,
+    ) -> Result<BytesN<32>, EscrowError> {
+        customer.require_auth();
+        Self::require_caller_chosen_id(&quote.payment_id)?;
+
+        if env.ledger().sequence() > quote.expiry {
+            return Err(EscrowError::QuoteExpired);
+        }
+        if matches!(&quote.customer, Some(bound) if *bound != customer) {
+            return Err(EscrowError::QuoteCustomerMismatch);
+        }
+        let public_key = Self::get_merchant_signing_key(env.clone(), quote.merchant.clone())
+            .ok_or(EscrowError::MerchantKeyNotFound)?;
+        let nonce_key = DataKey::QuoteNonce(quote.merchant.clone(), quote.nonce);
+        if env.storage().persistent().has(&nonce_key) {
+            return Err(EscrowError::QuoteAlreadyUsed);
+        }
+
+        let message = (env.current_contract_address(), quote.clone()).to_xdr(&env);
+        env.crypto()
+            .ed25519_verify(&public_key, &message, &signature);
+        env.storage().persistent().set(&nonce_key, &true);
+
+        let payment_id = Self::open_payment(
+            env.clone(),
+            customer.clone(),
+            customer.clone(),
+            quote.payment_id,
+            quote.merchant.clone(),
+            quote.token.clone(),
+            quote.amount,
+            None,
+        )?;
+        Self::transfer_to_contract(&env, &quote.token, &customer, quote.amount);
+
+        QuoteRedeemedEvent {
+            payment_id: payment_id.clone(),
+            merchant: quote.merchant,
+            customer,
+            nonce: quote.nonce,
+        }
+        .publish(&env);
+        Ok(payment_id)
+    }
+
+    pub fn create_subscription(
+        env: Env,
+        customer: Address,
+        subscription_id: BytesN<32>,
+        terms: SubscriptionTerms,
+    ) -> Result<(), EscrowError> {
+        customer.require_auth();
+        Self::require_not_paused(&env, PauseScope::Deposits)?;
+
+        if terms.amount_per_period <= 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+        if !Self::is_token_allowed(env.clone(), terms.token.clone()) {
+            return Err(EscrowError::TokenNotAllowed);
+        }
+        if env
+            .storage()
+            .persistent()
+            .has(&DataKey::Subscription(subscription_id.clone()))
+        {
+            return Err(EscrowError::SubscriptionAlreadyExists);
+        }
+        // The allowance has to stay live until the last period can be charged.
+        terms
+            .period_ledgers
+            .checked_mul(terms.max_periods)
+            .filter(|span| *span > 0 && *span < env.storage().max_ttl())
+            .ok_or(EscrowError::InvalidSubscription)?;
+        let budget = terms
+            .amount_per_period
+            .checked_mul(terms.max_periods as i128)
+            .ok_or(EscrowError::InvalidSubscription)?;
+
+        let now = env.ledger().sequence();
+        Self::adjust_subscription_allowance(&env, &terms.token, &customer, budget);
+
+        let subscription = Subscription {
+            subscription_id: subscription_id.clone(),
+            customer: customer.clone(),
+            merchant: terms.merchant.clone(),
+            token: terms.token.clone(),
+            amount_per_period: terms.amount_per_period,
+            period_ledgers: terms.period_ledgers,
+            max_periods: terms.max_periods,
+            periods_charged: 0,
+            next_charge_ledger: now,
+            active: true,
+        };
+        env.storage().persistent().set(
+            &DataKey::Subscription(subscription_id.clone()),
+            &subscription,
+        );
+
+        SubscriptionCreatedEvent {
+            subscription_id,
+            customer,
+            merchant: terms.merchant,
+            token: terms.token,
+            amount_per_period: terms.amount_per_period,
+            period_ledgers: terms.period_ledgers,
+            max_periods: terms.max_periods,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    /// Pulls the next period's amount from the customer's allowance into a
+    /// new escrowed payment. Anyone may call this once the period is due, so
+    /// merchants can run their own keeper.
+    pub fn charge_period(env: Env, subscription_id: BytesN<32>) -> Result<BytesN<32>, EscrowError> {
+        let mut subscription = Self::get_subscription(env.clone(), subscription_id.clone())?;
+        if !subscription.active {
+            return Err(EscrowError::SubscriptionInactive);
+        }
+        if subscription.periods_charged >= subscription.max_periods {
+            return Err(EscrowError::SubscriptionComplete);
+        }
+        if env.ledger().sequence() < subscription.next_charge_ledger {
+            return Err(EscrowError::ChargeNotDue);
+        }
+
+        let period = subscription.periods_charged;
+        let payment_id =
+            Self::get_subscription_payment_id(env.clone(), subscription_id.clone(), period);
+        Self::open_payment(
+            env.clone(),
+            subscription.customer.clone(),
+            subscription.customer.clone(),
+            payment_id.clone(),
+            subscription.merchant.clone(),
+            subscription.token.clone(),
+            subscription.amount_per_period,
+            None,
+        )?;
+        let contract = env.current_contract_address();
+        token::Client::new(&env, &subscription.token).transfer_from(
+            &contract,
+            &subscription.customer,
+            &contract,
+            &subscription.amount_per_period,
+        );
+
+        subscription.periods_charged += 1;
+        subscription.next_charge_ledger = subscription
+            .next_charge_ledger
+            .saturating_add(subscription.period_ledgers);
+        env.storage().persistent().set(
+            &DataKey::Subscription(subscription_id.clone()),
+            &subscription,
+        );
+
+        SubscriptionChargedEvent {
+            subscription_id,
+            payment_id: payment_id.clone(),
+            period,
+            amount: subscription.amount_per_period,
+            next_charge_ledger: subscription.next_charge_ledger,
+        }
+        .publish(&env);
+        Ok(payment_id)
+    }
+
+    pub fn cancel_subscription(env: Env, subscription_id: BytesN<32>) -> Result<(), EscrowError> {
+        let mut subscription = Self::get_subscription(env.clone(), subscription_id.clone())?;
+        subscription.customer.require_auth();
+        if !subscription.active {
+            return Err(EscrowError::SubscriptionInactive);
+        }
+
+        let unspent = subscription
+            .amount_per_period
+            .saturating_mul((subscription.max_periods - subscription.periods_charged) as i128);
+        Self::adjust_subscription_allowance(
+            &env,
+            &subscription.token,
+            &subscription.customer,
+            -unspent,
+        );
+        subscription.active = false;
+        env.storage().persistent().set(
+            &DataKey::Subscription(subscription_id.clone()),
+            &subscription,
+        );
+
+        SubscriptionCancelledEvent {
+            subscription_id,
+            customer: subscription.customer,
+            periods_charged: subscription.periods_charged,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn top_up(env: Env, payment_id: BytesN<32>, amount: i128) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Deposits)?;
+
+        if amount <= 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        payment.payer.require_auth();
+        Self::require_releasable(&env, &payment)?;
+        if !payment.milestones.is_empty() {
+            return Err(EscrowError::MilestonesActive);
+        }
+        if payment.stream_end_ledger.is_some() {
+            return Err(EscrowError::StreamActive);
+        }
+
+        let total = payment.amount.saturating_add(amount);
+        if matches!(payment.target_amount, Some(target) if total > target) {
+            return Err(EscrowError::TargetExceeded);
+        }
+        Self::require_within_limits(&env, &payment.token, amount, total)?;
+
+        Self::transfer_to_contract(&env, &payment.token, &payment.payer, amount);
+        Self::increase_locked_total(&env, &payment.token, amount);
+
+        payment.amount = total;
+        payment.status = Self::settled_status(&payment);
+        Self::save_payment(&env, &payment);
+
+        TopUpEvent {
+            payment_id,
+            payer: payment.payer,
+            amount,
+            total,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn release(env: Env, caller: Address, payment_id: BytesN<32>) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+        Self::require_role(&env, &caller, Role::Releaser)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        Self::require_releasable(&env, &payment)?;
+
+        let remaining = Self::remaining_amount(&payment);
+        if remaining <= 0 {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+
+        let (fee, payouts) = Self::pay_merchant(&env, &payment, remaining);
+        payment.released_amount += remaining;
+        payment.status = PaymentStatus::Released;
+        Self::settle_milestones(&mut payment, MilestoneStatus::Released);
+        Self::save_payment(&env, &payment);
+
+        ReleaseEvent {
+            payment_id,
+            merchant: payment.merchant,
+            amount: remaining,
+            fee,
+            payouts,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn release_partial(
+        env: Env,
+        caller: Address,
+        payment_id: BytesN<32>,
+        amount: i128,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+        Self::require_role(&env, &caller, Role::Releaser)?;
+
+        if amount <= 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        Self::require_releasable(&env, &payment)?;
+        if !payment.milestones.is_empty() {
+            return Err(EscrowError::MilestonesActive);
+        }
+        if payment.stream_end_ledger.is_some() {
+            return Err(EscrowError::StreamActive);
+        }
+
+        let remaining = Self::remaining_amount(&payment);
+        if remaining <= 0 {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+        if amount > remaining {
+            return Err(EscrowError::ReleaseExceedsRemaining);
+        }
+
+        let (fee, payouts) = Self::pay_merchant(&env, &payment, amount);
+        payment.released_amount += amount;
+        if Self::remaining_amount(&payment) == 0 {
+            payment.status = PaymentStatus::Released;
+        }
+        Self::save_payment(&env, &payment);
+
+        PartialReleaseEvent {
+            payment_id,
+            merchant: payment.merchant,
+            amount,
+            fee,
+            payouts,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn confirm_delivery(env: Env, payment_id: BytesN<32>) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        payment.customer.require_auth();
+        Self::require_releasable(&env, &payment)?;
+
+        let remaining = Self::remaining_amount(&payment);
+        if remaining <= 0 {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+
+        let (fee, payouts) = Self::pay_merchant(&env, &payment, remaining);
+        payment.released_amount += remaining;
+        payment.status = PaymentStatus::Released;
+        payment.dispute_window_end = env.ledger().sequence();
+        Self::settle_milestones(&mut payment, MilestoneStatus::Released);
+        Self::save_payment(&env, &payment);
+
+        DeliveryConfirmedEvent {
+            payment_id,
+            customer: payment.customer,
+            merchant: payment.merchant,
+            amount: remaining,
+            fee,
+            payouts,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn claim_after_window(env: Env, payment_id: BytesN<32>) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
+        }
+        if payment.status == PaymentStatus::Released {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+        if !Self::is_open(&payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        Self::require_no_milestone_dispute(&payment)?;
+        if !payment.release_on_window_close {
+            return Err(EscrowError::AutoReleaseDisabled);
+        }
+        if env.ledger().sequence() <= payment.dispute_window_end {
+            return Err(EscrowError::DisputeWindowOpen);
+        }
+
+        let remaining = Self::remaining_amount(&payment);
+        if remaining <= 0 {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+
+        let (fee, payouts) = Self::pay_merchant(&env, &payment, remaining);
+        payment.released_amount += remaining;
+        payment.status = PaymentStatus::Released;
+        Self::settle_milestones(&mut payment, MilestoneStatus::Released);
+        Self::save_payment(&env, &payment);
+
+        WindowCloseReleaseEvent {
+            payment_id,
+            merchant: payment.merchant,
+            amount: remaining,
+            fee,
+            payouts,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn expire(env: Env, payment_id: BytesN<32>) -> Result<(), EscrowError> {
+        Self::refund(env, payment_id)
+    }
+
+    pub fn refund(env: Env, payment_id: BytesN<32>) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Refunds)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        Self::require_expirable(&env, &payment)?;
+
+        let remaining = Self::remaining_amount(&payment);
+        if remaining <= 0 {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+
+        let recipient = Self::refund_recipient(&payment);
+        Self::pay_out(&env, &payment, &recipient, remaining);
+        payment.refunded_amount += remaining;
+        payment.status = PaymentStatus::Expired;
+        Self::settle_milestones(&mut payment, MilestoneStatus::Refunded);
+        Self::save_payment(&env, &payment);
+
+        ExpiryEvent {
+            payment_id,
+            customer: payment.customer,
+            recipient,
+            amount: remaining,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn merchant_refund(
+        env: Env,
+        payment_id: BytesN<32>,
+        amount: i128,
+    ) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Refunds)?;
+
+        if amount <= 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        payment.merchant.require_auth();
+        Self::require_refundable(&payment)?;
+
+        let remaining = Self::remaining_amount(&payment);
+        if amount > remaining {
+            return Err(EscrowError::RefundExceedsRemaining);
+        }
+        if amount < remaining && !payment.milestones.is_empty() {
+            return Err(EscrowError::MilestonesActive);
+        }
+        if amount < remaining && payment.stream_end_ledger.is_some() {
+            return Err(EscrowError::StreamActive);
+        }
+
+        let recipient = Self::refund_recipient(&payment);
+        Self::pay_out(&env, &payment, &recipient, amount);
+        payment.refunded_amount += amount;
+        let remaining = remaining - amount;
+        payment.status = if remaining == 0 {
+            PaymentStatus::Refunded
+        } else {
+            Self::settled_status(&payment)
+        };
+        Self::settle_milestones(&mut payment, MilestoneStatus::Refunded);
+        Self::save_payment(&env, &payment);
+
+        RefundEvent {
+            payment_id,
+            customer: payment.customer,
+            recipient,
+            amount,
+            remaining,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn extend_expiry(
+        env: Env,
+        payment_id: BytesN<32>,
+        additional_ledgers: u32,
+    ) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Refunds)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        let config = Self::get_config(env.clone());
+        payment.customer.require_auth();
+        // Extending a payment that pays the merchant on window close delays
+        // their payout, so they have to agree to it.
+        if config.extension_requires_merchant || payment.release_on_window_close {
+            payment.merchant.require_auth();
+        }
+
+        if additional_ledgers == 0 {
+            return Err(EscrowError::InvalidTtl);
+        }
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
+        }
+        if !Self::is_open(&payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        let now = env.ledger().sequence();
+        if now > payment.expiry {
+            return Err(EscrowError::PaymentExpired);
+        }
+
+        let previous_expiry = payment.expiry;
+        let expiry = previous_expiry.saturating_add(additional_ledgers);
+        if expiry - now > config.max_ttl_ledgers {
+            return Err(EscrowError::TtlExceedsMaximum);
+        }
+        payment.expiry = expiry;
+        // A dispute window that has already closed stays closed.
+        if now <= payment.dispute_window_end {
+            let window_end = expiry.min(now.saturating_add(config.max_dispute_window_ledgers));
+            payment.dispute_window_end = payment.dispute_window_end.max(window_end);
+        }
+        Self::save_payment(&env, &payment);
+
+        ExpiryExtendedEvent {
+            payment_id,
+            previous_expiry,
+            expiry,
+            dispute_window_end: payment.dispute_window_end,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn dispute(
+        env: Env,
+        caller: Address,
+        payment_id: BytesN<32>,
+        reason: String,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Disputes)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeAlreadyOpen);
+        }
+        if !Self::is_open(&payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        if Self::has_milestone_dispute(&payment) {
+            return Err(EscrowError::DisputeAlreadyOpen);
+        }
+        Self::require_can_dispute(&env, &payment, &caller, payment.dispute_window_end)?;
+
+        let bond = Self::collect_dispute_bond(
+            &env,
+            &payment.token,
+            &caller,
+            Self::remaining_amount(&payment),
+        );
+
+        payment.status = PaymentStatus::Disputed;
+        payment.dispute_reason = Some(reason.clone());
+        payment.dispute_opened_by = Some(caller.clone());
+        payment.dispute_bond = bond;
+        Self::save_payment(&env, &payment);
+
+        DisputeOpenedEvent {
+            payment_id,
+            opened_by: caller,
+            reason,
+            bond,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn resolve_dispute(
+        env: Env,
+        caller: Address,
+        payment_id: BytesN<32>,
+        winner: Address,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Disputes)?;
+        Self::require_role(&env, &caller, Role::Arbiter)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        if payment.status != PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeNotOpen);
+        }
+        if winner != payment.customer && winner != payment.merchant {
+            return Err(EscrowError::InvalidDisputeWinner);
+        }
+
+        let remaining = Self::remaining_amount(&payment);
+        if remaining <= 0 {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+
+        let (fee, payouts) = if winner == payment.merchant {
+            payment.status = PaymentStatus::Released;
+            payment.released_amount += remaining;
+            Self::settle_milestones(&mut payment, MilestoneStatus::Released);
+            Self::pay_merchant(&env, &payment, remaining)
+        } else {
+            payment.status = PaymentStatus::Expired;
+            payment.refunded_amount += remaining;
+            Self::settle_milestones(&mut payment, MilestoneStatus::Refunded);
+            let recipient = Self::refund_recipient(&payment);
+            Self::pay_out(&env, &payment, &recipient, remaining);
+            (0, Self::single_payout(&env, &recipient, remaining))
+        };
+        let bond = payment.dispute_bond;
+        let bond_returned = Self::settle_dispute_bond(
+            &env,
+            &payment.token,
+            &payment.dispute_opened_by,
+            bond,
+            &winner,
+        );
+        Self::save_payment(&env, &payment);
+
+        DisputeResolvedEvent {
+            payment_id,
+            winner,
+            amount: remaining,
+            fee,
+            payouts,
+            bond,
+            bond_returned,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn approve_milestone(
+        env: Env,
+        caller: Address,
+        payment_id: BytesN<32>,
+        index: u32,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        if caller != payment.customer {
+            Self::require_role(&env, &caller, Role::Arbiter)?;
+        }
+        Self::require_tranche_releasable(&env, &payment)?;
+        let mut milestone = Self::load_milestone(&payment, index)?;
+        if milestone.status == MilestoneStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
+        }
+        if milestone.status != MilestoneStatus::Pending {
+            return Err(EscrowError::MilestoneNotPending);
+        }
+
+        let (fee, payouts) = Self::pay_merchant(&env, &payment, milestone.amount);
+        payment.released_amount += milestone.amount;
+        milestone.status = MilestoneStatus::Released;
+        payment.milestones.set(index, milestone.clone());
+        payment.status = Self::settled_status(&payment);
+        Self::save_payment(&env, &payment);
+
+        MilestoneApprovedEvent {
+            payment_id,
+            index,
+            approved_by: caller,
+            amount: milestone.amount,
+            fee,
+            payouts,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn refund_milestone(
+        env: Env,
+        payment_id: BytesN<32>,
+        index: u32,
+    ) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Refunds)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        Self::require_tranche_refundable(&payment)?;
+        let mut milestone = Self::load_milestone(&payment, index)?;
+        if milestone.status == MilestoneStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
+        }
+        if milestone.status != MilestoneStatus::Pending {
+            return Err(EscrowError::MilestoneNotPending);
+        }
+        match milestone.deadline {
+            Some(deadline) if env.ledger().sequence() > deadline => {}
+            _ => return Err(EscrowError::MilestoneDeadlineNotReached),
+        }
+
+        let recipient = Self::refund_recipient(&payment);
+        Self::pay_out(&env, &payment, &recipient, milestone.amount);
+        payment.refunded_amount += milestone.amount;
+        milestone.status = MilestoneStatus::Refunded;
+        payment.milestones.set(index, milestone.clone());
+        payment.status = Self::settled_status(&payment);
+        Self::save_payment(&env, &payment);
+
+        MilestoneRefundedEvent {
+            payment_id,
+            index,
+            customer: payment.customer,
+            recipient,
+            amount: milestone.amount,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn dispute_milestone(
+        env: Env,
+        caller: Address,
+        payment_id: BytesN<32>,
+        index: u32,
+        reason: String,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Disputes)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeAlreadyOpen);
+        }
+        if !Self::is_open(&payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        let mut milestone = Self::load_milestone(&payment, index)?;
+        if milestone.status == MilestoneStatus::Disputed {
+            return Err(EscrowError::DisputeAlreadyOpen);
+        }
+        if milestone.status != MilestoneStatus::Pending {
+            return Err(EscrowError::MilestoneNotPending);
+        }
+        // A tranche stays disputable until it can be refunded for a missed
+        // deadline, or until the payment expires if it has no deadline.
+        let window_end = milestone
+            .deadline
+            .unwrap_or(payment.expiry)
+            .max(payment.dispute_window_end);
+        Self::require_can_dispute(&env, &payment, &caller, window_end)?;
+
+        let bond = Self::collect_dispute_bond(&env, &payment.token, &caller, milestone.amount);
+        milestone.status = MilestoneStatus::Disputed;
+        milestone.dispute_opened_by = Some(caller.clone());
+        milestone.dispute_bond = bond;
+        payment.milestones.set(index, milestone);
+        Self::save_payment(&env, &payment);
+
+        MilestoneDisputeOpenedEvent {
+            payment_id,
+            index,
+            opened_by: caller,
+            reason,
+            bond,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn resolve_milestone_dispute(
+        env: Env,
+        caller: Address,
+        payment_id: BytesN<32>,
+        index: u32,
+        winner: Address,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Disputes)?;
+        Self::require_role(&env, &caller, Role::Arbiter)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        let mut milestone = Self::load_milestone(&payment, index)?;
+        if milestone.status != MilestoneStatus::Disputed {
+            return Err(EscrowError::DisputeNotOpen);
+        }
+        if winner != payment.customer && winner != payment.merchant {
+            return Err(EscrowError::InvalidDisputeWinner);
+        }
+
+        let (fee, payouts) = if winner == payment.merchant {
+            milestone.status = MilestoneStatus::Released;
+            payment.released_amount += milestone.amount;
+            Self::pay_merchant(&env, &payment, milestone.amount)
+        } else {
+            milestone.status = MilestoneStatus::Refunded;
+            payment.refunded_amount += milestone.amount;
+            let recipient = Self::refund_recipient(&payment);
+            Self::pay_out(&env, &payment, &recipient, milestone.amount);
+            (0, Self::single_payout(&env, &recipient, milestone.amount))
+        };
+        let bond = milestone.dispute_bond;
+        let bond_returned = Self::settle_dispute_bond(
+            &env,
+            &payment.token,
+            &milestone.dispute_opened_by,
+            bond,
+            &winner,
+        );
+        payment.milestones.set(index, milestone.clone());
+        payment.status = Self::settled_status(&payment);
+        Self::save_payment(&env, &payment);
+
+        MilestoneDisputeResolvedEvent {
+            payment_id,
+            index,
+            winner,
+            amount: milestone.amount,
+            fee,
+            payouts,
+            bond,
+            bond_returned,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn withdraw_vested(env: Env, payment_id: BytesN<32>) -> Result<i128, EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        payment.merchant.require_auth();
+        let vested = Self::require_open_stream(&env, &payment)?;
+
+        let amount = vested - payment.released_amount;
+        if amount <= 0 {
+            return Err(EscrowError::NothingVested);
+        }
+
+        let (fee, payouts) = Self::pay_merchant(&env, &payment, amount);
+        payment.released_amount += amount;
+        payment.status = Self::settled_status(&payment);
+        Self::save_payment(&env, &payment);
+
+        VestedWithdrawalEvent {
+            payment_id,
+            merchant: payment.merchant,
+            amount,
+            fee,
+            payouts,
+            vested,
+        }
+        .publish(&env);
+        Ok(amount)
+    }
+
+    pub fn cancel_stream(env: Env, payment_id: BytesN<32>) -> Result<(), EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Refunds)?;
+
+        let mut payment = Self::load_payment(&env, &payment_id)?;
+        payment.customer.require_auth();
+        let vested = Self::require_open_stream(&env, &payment)?;
+
+        let merchant_amount = vested - payment.released_amount;
+        let refunded = payment.amount - vested;
+        let (fee, payouts) = if merchant_amount > 0 {
+            Self::pay_merchant(&env, &payment, merchant_amount)
+        } else {
+            (0, Vec::new(&env))
+        };
+        let recipient = Self::refund_recipient(&payment);
+        if refunded > 0 {
+            Self::pay_out(&env, &payment, &recipient, refunded);
+        }
+        payment.released_amount += merchant_amount;
+        payment.refunded_amount += refunded;
+        payment.status = Self::settled_status(&payment);
+        Self::save_payment(&env, &payment);
+
+        StreamCancelledEvent {
+            payment_id,
+            customer: payment.customer,
+            recipient,
+            merchant_amount,
+            fee,
+            payouts,
+            refunded,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn propose_admin(env: Env, caller: Address, new_admin: Address) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        env.storage()
+            .instance()
+            .set(&DataKey::PendingAdmin, &new_admin);
+
+        AdminProposedEvent {
+            admin: caller,
+            pending_admin: new_admin,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn accept_admin(env: Env, new_admin: Address) -> Result<(), EscrowError> {
+        new_admin.require_auth();
+
+        let pending_admin =
+            Self::get_pending_admin(env.clone()).ok_or(EscrowError::NoPendingAdmin)?;
+        if pending_admin != new_admin {
+            return Err(EscrowError::NotPendingAdmin);
+        }
+
+        let previous_admin = Self::get_admin(env.clone());
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+        Self::transfer_roles(&env, &previous_admin, &new_admin);
+
+        AdminAcceptedEvent {
+            previous_admin,
+            admin: new_admin,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn cancel_admin_proposal(env: Env, caller: Address) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        let pending_admin =
+            Self::get_pending_admin(env.clone()).ok_or(EscrowError::NoPendingAdmin)?;
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+
+        AdminProposalCancelledEvent {
+            admin: caller,
+            pending_admin,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn grant_role(
+        env: Env,
+        caller: Address,
+        role: Role,
+        account: Address,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role_admin(&env, &caller)?;
+
+        env.storage()
+            .persistent()
+            .set(&DataKey::Role(role, account.clone()), &true);
+
+        RoleGrantedEvent {
+            role,
+            account,
+            sender: caller,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn revoke_role(
+        env: Env,
+        caller: Address,
+        role: Role,
+        account: Address,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role_admin(&env, &caller)?;
+
+        env.storage()
+            .persistent()
+            .remove(&DataKey::Role(role, account.clone()));
+
+        RoleRevokedEvent {
+            role,
+            account,
+            sender: caller,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn has_role(env: Env, role: Role, account: Address) -> bool {
+        env.storage()
+            .persistent()
+            .has(&DataKey::Role(role, account))
+    }
+
+    pub fn pause(env: Env, caller: Address, scope: PauseScope) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::Pauser)?;
+
+        Self::set_paused(&env, scope, true);
+
+        PausedEvent {
+            scope,
+            sender: caller,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn unpause(env: Env, caller: Address, scope: PauseScope) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::Pauser)?;
+
+        Self::set_paused(&env, scope, false);
+
+        UnpausedEvent {
+            scope,
+            sender: caller,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn set_default_ttl_ledgers(
+        env: Env,
+        caller: Address,
+        default_ttl_ledgers: u32,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        let mut config = Self::get_config(env.clone());
+        Self::validate_ttl(&config, default_ttl_ledgers)?;
+
+        let previous_ttl_ledgers = config.default_ttl_ledgers;
+        config.default_ttl_ledgers = default_ttl_ledgers;
+        env.storage().instance().set(&DataKey::Config, &config);
+
+        DefaultTtlUpdatedEvent {
+            previous_ttl_ledgers,
+            default_ttl_ledgers,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn set_config(env: Env, caller: Address, config: EscrowConfig) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+        Self::validate_config(&config)?;
+
+        env.storage().instance().set(&DataKey::Config, &config);
+
+        ConfigUpdatedEvent { config }.publish(&env);
+        Ok(())
+    }
+
+    pub fn allow_token(env: Env, caller: Address, token: Address) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        let mut allowed = Self::get_allowed_tokens(env.clone());
+        if !allowed.contains(&token) {
+            allowed.push_back(token.clone());
+            env.storage()
+                .instance()
+                .set(&DataKey::AllowedTokens, &allowed);
+        }
+
+        TokenAllowedEvent { token }.publish(&env);
+        Ok(())
+    }
+
+    pub fn disallow_token(env: Env, caller: Address, token: Address) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        let mut allowed = Self::get_allowed_tokens(env.clone());
+        let index = allowed
+            .first_index_of(&token)
+            .ok_or(EscrowError::TokenNotAllowed)?;
+        allowed.remove(index);
+        env.storage()
+            .instance()
+            .set(&DataKey::AllowedTokens, &allowed);
+
+        TokenDisallowedEvent { token }.publish(&env);
+        Ok(())
+    }
+
+    pub fn set_token_limits(
+        env: Env,
+        caller: Address,
+        token: Address,
+        limits: TokenLimits,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+        Self::validate_token_limits(&limits)?;
+
+        env.storage()
+            .instance()
+            .set(&DataKey::TokenLimits(token.clone()), &limits);
+
+        TokenLimitsUpdatedEvent { token, limits }.publish(&env);
+        Ok(())
+    }
+
+    pub fn set_fee_schedule(
+        env: Env,
+        caller: Address,
+        schedule: FeeSchedule,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+        Self::validate_fee_schedule(&schedule)?;
+
+        env.storage()
+            .instance()
+            .set(&DataKey::FeeSchedule, &schedule);
+
+        FeeScheduleUpdatedEvent { schedule }.publish(&env);
+        Ok(())
+    }
+
+    pub fn set_fee_tier(
+        env: Env,
+        caller: Address,
+        tier: u32,
+        multiplier_bps: u32,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+        if multiplier_bps > MAX_FEE_MULTIPLIER_BPS {
+            return Err(EscrowError::InvalidConfig);
+        }
+
+        env.storage()
+            .instance()
+            .set(&DataKey::FeeTier(tier), &multiplier_bps);
+
+        FeeTierUpdatedEvent {
+            tier,
+            multiplier_bps: Some(multiplier_bps),
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    /// Merchants still assigned to a cleared tier fall back to the global
+    /// rate until they are reassigned.
+    pub fn clear_fee_tier(env: Env, caller: Address, tier: u32) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+        if Self::get_fee_tier(env.clone(), tier).is_none() {
+            return Err(EscrowError::FeeTierNotFound);
+        }
+
+        env.storage().instance().remove(&DataKey::FeeTier(tier));
+
+        FeeTierUpdatedEvent {
+            tier,
+            multiplier_bps: None,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn set_merchant_fee_tier(
+        env: Env,
+        caller: Address,
+        merchant: Address,
+        tier: u32,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+
+        if Self::get_fee_tier(env.clone(), tier).is_none() {
+            return Err(EscrowError::FeeTierNotFound);
+        }
+        env.storage()
+            .persistent()
+            .set(&DataKey::MerchantFeeTier(merchant.clone()), &tier);
+
+        MerchantFeeTierUpdatedEvent {
+            merchant,
+            tier: Some(tier),
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn clear_merchant_fee_tier(
+        env: Env,
+        caller: Address,
+        merchant: Address,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+
+        env.storage()
+            .persistent()
+            .remove(&DataKey::MerchantFeeTier(merchant.clone()));
+
+        MerchantFeeTierUpdatedEvent {
+            merchant,
+            tier: None,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn set_merchant_fee_override(
+        env: Env,
+        caller: Address,
+        merchant: Address,
+        schedule: FeeSchedule,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+        Self::validate_fee_schedule(&schedule)?;
+
+        env.storage()
+            .persistent()
+            .set(&DataKey::MerchantFeeOverride(merchant.clone()), &schedule);
+
+        MerchantFeeOverrideUpdatedEvent {
+            merchant,
+            schedule: Some(schedule),
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn clear_merchant_fee_override(
+        env: Env,
+        caller: Address,
+        merchant: Address,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_role(&env, &caller, Role::FeeManager)?;
+
+        env.storage()
+            .persistent()
+            .remove(&DataKey::MerchantFeeOverride(merchant.clone()));
+
+        MerchantFeeOverrideUpdatedEvent {
+            merchant,
+            schedule: None,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn withdraw_fees(
+        env: Env,
+        caller: Address,
+        token: Address,
+        recipient: Address,
+        amount: i128,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_not_paused(&env, PauseScope::Releases)?;
+        Self::require_role(&env, &caller, Role::FeeCollector)?;
+
+        if amount <= 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+        let accrued = Self::get_accrued_fees(env.clone(), token.clone());
+        if amount > accrued {
+            return Err(EscrowError::InsufficientFees);
+        }
+
+        env.storage()
+            .instance()
+            .set(&DataKey::AccruedFees(token.clone()), &(accrued - amount));
+        Self::transfer_from_contract(&env, &token, &recipient, amount);
+
+        FeesWithdrawnEvent {
+            token,
+            recipient,
+            amount,
+        }
+        .publish(&env);
+        Ok(())
+    }
+
+    pub fn upgrade(
+        env: Env,
+        caller: Address,
+        new_wasm_hash: BytesN<32>,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        env.deployer()
+            .update_current_contract_wasm(new_wasm_hash.clone());
+
+        UpgradedEvent { new_wasm_hash }.publish(&env);
+        Ok(())
+    }
+
+    pub fn migrate(
+        env: Env,
+        caller: Address,
+        payment_ids: Vec<BytesN<32>>,
+    ) -> Result<(), EscrowError> {
+        caller.require_auth();
+        Self::require_admin(&env, &caller)?;
+
+        let from_version = Self::version(env.clone());
+        if from_version < ROLES_SCHEMA_VERSION {
+            Self::grant_all_roles(&env, &caller);
+        }
+        if from_version < SCHEMA_VERSION {
+            env.storage()
+                .instance()
+                .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+            MigratedEvent {
+                from_version,
+                to_version: SCHEMA_VERSION,
+            }
+            .publish(&env);
+        }
+
+        for payment_id in payment_ids.iter() {
+            Self::migrate_legacy_payment(&env, &payment_id);
+        }
+        Ok(())
+    }
+
+    pub fn version(env: Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&DataKey::SchemaVersion)
+            .unwrap_or(1)
+    }
+
+    pub fn get_payment(env: Env, payment_id: BytesN<32>) -> Result<PaymentEscrow, EscrowError> {
+        if let Some(payment) = env
+            .storage()
+            .persistent()
+            .get(&DataKey::PaymentV2(payment_id.clone()))
+        {
+            return Ok(payment);
+        }
+        env.storage()
+            .persistent()
+            .get::<_, PaymentEscrowV1>(&DataKey::Payment(payment_id))
+            .map(|legacy| legacy.into_current(&env, Self::get_usdc_token(env.clone())))
+            .ok_or(EscrowError::PaymentNotFound)
+    }
+
+    pub fn get_milestones(env: Env, payment_id: BytesN<32>) -> Result<Vec<Milestone>, EscrowError> {
+        Ok(Self::get_payment(env, payment_id)?.milestones)
+    }
+
+    pub fn get_milestone(
+        env: Env,
+        payment_id: BytesN<32>,
+        index: u32,
+    ) -> Result<Milestone, EscrowError> {
+        Self::load_milestone(&Self::get_payment(env, payment_id)?, index)
+    }
+
+    pub fn get_vested_amount(env: Env, payment_id: BytesN<32>) -> Result<i128, EscrowError> {
+        let payment = Self::get_payment(env.clone(), payment_id)?;
+        let end_ledger = payment.stream_end_ledger.ok_or(EscrowError::NotStream)?;
+        Ok(Self::vested_amount(&env, &payment, end_ledger))
+    }
+
+    pub fn get_payment_request(
+        env: Env,
+        payment_id: BytesN<32>,
+    ) -> Result<PaymentRequest, EscrowError> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PaymentRequest(payment_id))
+            .ok_or(EscrowError::PaymentRequestNotFound)
+    }
+
+    pub fn get_merchant_signing_key(env: Env, merchant: Address) -> Option<BytesN<32>> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::MerchantSigningKey(merchant))
+    }
+
+    pub fn is_quote_nonce_used(env: Env, merchant: Address, nonce: u64) -> bool {
+        env.storage()
+            .persistent()
+            .has(&DataKey::QuoteNonce(merchant, nonce))
+    }
+
+    pub fn get_payment_metadata(env: Env, payment_id: BytesN<32>) -> Option<PaymentMetadata> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PaymentMetadata(payment_id))
+    }
+
+    pub fn get_payment_by_reference(
+        env: Env,
+        external_ref: String,
+    ) -> Result<PaymentEscrow, EscrowError> {
+        let payment_id: BytesN<32> = env
+            .storage()
+            .persistent()
+            .get(&DataKey::PaymentReference(external_ref))
+            .ok_or(EscrowError::PaymentNotFound)?;
+        Self::get_payment(env, payment_id)
+    }
+
+    pub fn get_subscription(
+        env: Env,
+        subscription_id: BytesN<32>,
+    ) -> Result<Subscription, EscrowError> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Subscription(subscription_id))
+            .ok_or(EscrowError::SubscriptionNotFound)
+    }
+
+    /// Payment id used for the zero-based `period` of a subscription:
+    /// `"SUB:"` followed by the first 28 bytes of
+    /// `sha256(subscription_id || period as big-endian u32)`.
+    pub fn get_subscription_payment_id(
+        env: Env,
+        subscription_id: BytesN<32>,
+        period: u32,
+    ) -> BytesN<32> {
+        let mut preimage = Bytes::from_array(&env, &subscription_id.to_array());
+        preimage.extend_from_array(&period.to_be_bytes());
+        let digest = env.crypto().sha256(&preimage).to_array();
+
+        let mut payment_id = [0u8; 32];
+        payment_id[..4].copy_from_slice(&SUBSCRIPTION_PAYMENT_PREFIX);
+        payment_id[4..].copy_from_slice(&digest[..28]);
+        BytesN::from_array(&env, &payment_id)
+    }
+
+    pub fn get_balance(env: Env, payment_id: BytesN<32>) -> Result<i128, EscrowError> {
+        let payment = Self::get_payment(env, payment_id)?;
+        Ok(Self::remaining_amount(&payment))
+    }
+
+    pub fn get_expiry(env: Env, payment_id: BytesN<32>) -> Result<u32, EscrowError> {
+        Ok(Self::get_payment(env, payment_id)?.expiry)
+    }
+
+    pub fn get_admin(env: Env) -> Address {
+        env.storage().instance().get(&DataKey::Admin).unwrap()
+    }
+
+    pub fn get_pending_admin(env: Env) -> Option<Address> {
+        env.storage().instance().get(&DataKey::PendingAdmin)
+    }
+
+    pub fn get_pause_state(env: Env) -> PauseState {
+        env.storage()
+            .instance()
+            .get(&DataKey::PauseState)
+            .unwrap_or_default()
+    }
+
+    pub fn get_usdc_token(env: Env) -> Address {
+        env.storage().instance().get(&DataKey::UsdcToken).unwrap()
+    }
+
+    pub fn get_allowed_tokens(env: Env) -> Vec<Address> {
+        env.storage()
+            .instance()
+            .get(&DataKey::AllowedTokens)
+            .unwrap_or_else(|| Vec::from_array(&env, [Self::get_usdc_token(env.clone())]))
+    }
+
+    pub fn is_token_allowed(env: Env, token: Address) -> bool {
+        Self::get_allowed_tokens(env).contains(&token)
+    }
+
+    pub fn get_token_limits(env: Env, token: Address) -> TokenLimits {
+        env.storage()
+            .instance()
+            .get(&DataKey::TokenLimits(token))
+            .unwrap_or_default()
+    }
+
+    pub fn get_locked_total(env: Env, token: Address) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::LockedTotal(token))
+            .unwrap_or(0)
+    }
+
+    pub fn get_fee_schedule(env: Env) -> FeeSchedule {
+        env.storage()
+            .instance()
+            .get(&DataKey::FeeSchedule)
+            .unwrap_or_default()
+    }
+
+    pub fn get_fee_tier(env: Env, tier: u32) -> Option<u32> {
+        env.storage().instance().get(&DataKey::FeeTier(tier))
+    }
 
-const MAX_DISPUTE_WINDOW_LEDGERS: u32 = 51_840;
-const MAX_TTL_LEDGERS: u32 = 518_400;
+    pub fn get_merchant_fee_tier(env: Env, merchant: Address) -> Option<u32> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::MerchantFeeTier(merchant))
+    }
 
-#[contract]
-pub struct PaymentEscrowContract;
+    pub fn get_merchant_fee_override(env: Env, merchant: Address) -> Option<FeeSchedule> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::MerchantFeeOverride(merchant))
+    }
 
-#[contractimpl]
-impl PaymentEscrowContract {
-    pub fn __constructor(env: Env, admin: Address, usdc_token: Address, default_ttl_ledgers: u32) {
-        if default_ttl_ledgers == 0 {
-            panic!("Default TTL must be > 0");
+    pub fn preview_fee(env: Env, merchant: Address, amount: i128) -> i128 {
+        if amount <= 0 {
+            return 0;
         }
+        Self::merchant_fee(&env, &merchant, amount)
+    }
 
-        env.storage().instance().set(&DataKey::Admin, &admin);
-        env.storage()
-            .instance()
-            .set(&DataKey::UsdcToken, &usdc_token);
+    pub fn get_accrued_fees(env: Env, token: Address) -> i128 {
         env.storage()
             .instance()
-            .set(&DataKey::DefaultTtlLedgers, &default_ttl_ledgers);
+            .get(&DataKey::AccruedFees(token))
+            .unwrap_or(0)
     }
 
-    pub fn deposit(
+    pub fn get_config(env: Env) -> EscrowConfig {
+        if let Some(config) = env.storage().instance().get(&DataKey::Config) {
+            return config;
+        }
+        EscrowConfig {
+            default_ttl_ledgers: env
+                .storage()
+                .instance()
+                .get(&DataKey::DefaultTtlLedgers)
+                .unwrap(),
+            max_ttl_ledgers: DEFAULT_MAX_TTL_LEDGERS,
+            max_dispute_window_ledgers: DEFAULT_MAX_DISPUTE_WINDOW_LEDGERS,
+            dispute_bond_bps: 0,
+            forfeit_bond_to_fees: false,
+            extension_requires_merchant: false,
+        }
+    }
+
+    pub fn get_default_ttl_ledgers(env: Env) -> u32 {
+        Self::get_config(env).default_ttl_ledgers
+    }
+
+    pub fn get_max_dispute_window_ledgers(env: Env) -> u32 {
+        Self::get_config(env).max_dispute_window_ledgers
+    }
+
+    pub fn get_max_ttl_ledgers(env: Env) -> u32 {
+        Self::get_config(env).max_ttl_ledgers
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open_payment(
         env: Env,
+        payer: Address,
         customer: Address,
         payment_id: BytesN<32>,
         merchant: Address,
+        token: Address,
         amount: i128,
-        ttl_ledgers: u32,
-    ) -> BytesN<32> {
-        customer.require_auth();
+        options: Option<DepositOptions>,
+    ) -> Result<BytesN<32>, EscrowError> {
+        Self::require_not_paused(&env, PauseScope::Deposits)?;
 
         if amount <= 0 {
-            panic!("Amount must be > 0");
-        }
-        if ttl_ledgers == 0 {
-            panic!("TTL must be > 0");
+            return Err(EscrowError::InvalidAmount);
         }
-        if ttl_ledgers > MAX_TTL_LEDGERS {
-            panic!("TTL exceeds maximum");
+        if !Self::is_token_allowed(env.clone(), token.clone()) {
+            return Err(EscrowError::TokenNotAllowed);
         }
+        let options = options.unwrap_or_default();
+        let config = Self::get_config(env.clone());
+        let ttl_ledgers = options.ttl_ledgers.unwrap_or(config.default_ttl_ledgers);
+        Self::validate_ttl(&config, ttl_ledgers)?;
 
-        let key = DataKey::Payment(payment_id.clone());
-        if env.storage().persistent().has(&key) {
-            panic!("Payment ID already exists");
+        if Self::payment_exists(&env, &payment_id) {
+            return Err(EscrowError::PaymentAlreadyExists);
+        }
+        if let Ok(request) = Self::get_payment_request(env.clone(), payment_id.clone()) {
+            if request.funding_deadline >= env.ledger().sequence() {
+                return Err(EscrowError::PaymentAlreadyExists);
+            }
+            // An unfunded request past its deadline no longer reserves the id.
+            env.storage()
+                .persistent()
+                .remove(&DataKey::PaymentRequest(payment_id.clone()));
+        }
+        Self::require_within_limits(&env, &token, amount, amount)?;
+        if matches!(options.target_amount, Some(target) if target < amount) {
+            return Err(EscrowError::InvalidTargetAmount);
+        }
+        let expiry = env.ledger().sequence().saturating_add(ttl_ledgers);
+        let milestones = Self::build_milestones(&env, options.milestones, amount, expiry)?;
+        // Releasing on window close would pay every tranche at once and
+        // bypass the agreed schedule.
+        if !milestones.is_empty() && options.release_on_window_close {
+            return Err(EscrowError::InvalidMilestones);
+        }
+        // Milestone and stream payments cannot be topped up, so they must be
+        // fully funded at deposit.
+        if matches!(options.target_amount, Some(target) if target != amount)
+            && (!milestones.is_empty() || options.stream_duration_ledgers.is_some())
+        {
+            return Err(EscrowError::InvalidTargetAmount);
+        }
+        let payees = Self::build_payees(&env, options.payees)?;
+        let metadata =
+            Self::build_metadata(&env, options.external_ref, options.order_hash, options.memo)?;
+        if let Some(duration) = options.stream_duration_ledgers {
+            if duration == 0
+                || duration > ttl_ledgers
+                || !milestones.is_empty()
+                || options.release_on_window_close
+            {
+                return Err(EscrowError::InvalidStream);
+            }
         }
 
-        let usdc_token: Address = env.storage().instance().get(&DataKey::UsdcToken).unwrap();
-        let token_client = token::Client::new(&env, &usdc_token);
-        token_client.transfer(&customer, &env.current_contract_address(), &amount);
+        Self::increase_locked_total(&env, &token, amount);
 
-        let expiry = env.ledger().sequence().saturating_add(ttl_ledgers);
         let dispute_window_end = {
             let max_window_end = env
                 .ledger()
                 .sequence()
-                .saturating_add(MAX_DISPUTE_WINDOW_LEDGERS);
+                .saturating_add(config.max_dispute_window_ledgers);
             if expiry < max_window_end {
                 expiry
             } else {
@@ -148,271 +2577,715 @@ impl PaymentEscrowContract {
 
         let payment = PaymentEscrow {
             payment_id: payment_id.clone(),
+            token: token.clone(),
             amount,
             released_amount: 0,
+            refunded_amount: 0,
             merchant: merchant.clone(),
             customer: customer.clone(),
-            status: PaymentStatus::Pending,
+            payer: payer.clone(),
+            refund_to: options.refund_to,
+            status: if options.target_amount == Some(amount) {
+                PaymentStatus::Funded
+            } else {
+                PaymentStatus::Pending
+            },
             expiry,
             dispute_window_end,
             dispute_reason: None,
+            dispute_opened_by: None,
+            dispute_bond: 0,
+            release_on_window_close: options.release_on_window_close,
+            milestones,
+            created_ledger: env.ledger().sequence(),
+            stream_end_ledger: options
+                .stream_duration_ledgers
+                .map(|duration| env.ledger().sequence().saturating_add(duration)),
+            target_amount: options.target_amount,
+            payees,
         };
 
-        env.storage().persistent().set(&key, &payment);
+        Self::save_payment(&env, &payment);
+        if let Some(metadata) = &metadata {
+            env.storage()
+                .persistent()
+                .set(&DataKey::PaymentMetadata(payment_id.clone()), metadata);
+            if let Some(external_ref) = &metadata.external_ref {
+                env.storage().persistent().set(
+                    &DataKey::PaymentReference(external_ref.clone()),
+                    &payment_id,
+                );
+            }
+        }
 
         DepositEvent {
             payment_id: payment_id.clone(),
+            payer,
             customer,
             merchant,
+            token,
             amount,
             expiry,
+            metadata,
         }
         .publish(&env);
 
-        payment_id
+        Ok(payment_id)
     }
 
-    pub fn release(env: Env, caller: Address, payment_id: BytesN<32>) {
-        caller.require_auth();
-        Self::require_admin(&env, &caller);
+    fn require_admin(env: &Env, caller: &Address) -> Result<(), EscrowError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        if caller != &admin {
+            return Err(EscrowError::NotAdmin);
+        }
+        Ok(())
+    }
 
-        let mut payment = Self::get_payment(env.clone(), payment_id.clone());
-        Self::require_releasable(&env, &payment);
+    fn grant_all_roles(env: &Env, account: &Address) {
+        for role in ALL_ROLES {
+            env.storage()
+                .persistent()
+                .set(&DataKey::Role(role, account.clone()), &true);
+        }
+    }
 
-        let remaining = Self::remaining_amount(&payment);
-        if remaining <= 0 {
-            panic!("Payment fully released");
+    /// Moves every role still held by `from` to `to`, so a rotated-out admin
+    /// key keeps no privileges.
+    fn transfer_roles(env: &Env, from: &Address, to: &Address) {
+        for role in ALL_ROLES {
+            let previous = DataKey::Role(role, from.clone());
+            if !env.storage().persistent().has(&previous) {
+                continue;
+            }
+            env.storage().persistent().remove(&previous);
+            env.storage()
+                .persistent()
+                .set(&DataKey::Role(role, to.clone()), &true);
+
+            RoleRevokedEvent {
+                role,
+                account: from.clone(),
+                sender: to.clone(),
+            }
+            .publish(env);
+            RoleGrantedEvent {
+                role,
+                account: to.clone(),
+                sender: to.clone(),
+            }
+            .publish(env);
         }
+    }
 
-        Self::transfer_from_contract(&env, &payment.merchant, remaining);
-        payment.released_amount = payment.amount;
-        payment.status = PaymentStatus::Released;
-        env.storage()
-            .persistent()
-            .set(&DataKey::Payment(payment_id.clone()), &payment);
+    fn require_caller_chosen_id(payment_id: &BytesN<32>) -> Result<(), EscrowError> {
+        if payment_id.to_array()[..4] == SUBSCRIPTION_PAYMENT_PREFIX {
+            return Err(EscrowError::ReservedPaymentId);
+        }
+        Ok(())
+    }
 
-        ReleaseEvent {
-            payment_id,
-            merchant: payment.merchant,
-            amount: remaining,
+    fn require_role(env: &Env, caller: &Address, role: Role) -> Result<(), EscrowError> {
+        if !Self::has_role(env.clone(), role, caller.clone()) {
+            return Err(EscrowError::MissingRole);
         }
-        .publish(&env);
+        Ok(())
     }
 
-    pub fn release_partial(env: Env, caller: Address, payment_id: BytesN<32>, amount: i128) {
-        caller.require_auth();
-        Self::require_admin(&env, &caller);
+    /// The admin can always manage roles so that revoking every `RoleAdmin`
+    /// holder cannot lock role management.
+    fn require_role_admin(env: &Env, caller: &Address) -> Result<(), EscrowError> {
+        if Self::require_admin(env, caller).is_ok() {
+            return Ok(());
+        }
+        Self::require_role(env, caller, Role::RoleAdmin)
+    }
 
-        if amount <= 0 {
-            panic!("Release amount must be > 0");
+    fn require_not_paused(env: &Env, scope: PauseScope) -> Result<(), EscrowError> {
+        let state = Self::get_pause_state(env.clone());
+        let paused = match scope {
+            PauseScope::All => state.deposits || state.releases || state.refunds || state.disputes,
+            PauseScope::Deposits => state.deposits,
+            PauseScope::Releases => state.releases,
+            PauseScope::Refunds => state.refunds,
+            PauseScope::Disputes => state.disputes,
+        };
+        if paused {
+            return Err(EscrowError::Paused);
         }
+        Ok(())
+    }
 
-        let mut payment = Self::get_payment(env.clone(), payment_id.clone());
-        Self::require_releasable(&env, &payment);
+    fn set_paused(env: &Env, scope: PauseScope, paused: bool) {
+        let mut state = Self::get_pause_state(env.clone());
+        match scope {
+            PauseScope::All => {
+                state.deposits = paused;
+                state.releases = paused;
+                state.refunds = paused;
+                state.disputes = paused;
+            }
+            PauseScope::Deposits => state.deposits = paused,
+            PauseScope::Releases => state.releases = paused,
+            PauseScope::Refunds => state.refunds = paused,
+            PauseScope::Disputes => state.disputes = paused,
+        }
+        env.storage().instance().set(&DataKey::PauseState, &state);
+    }
 
-        let remaining = Self::remaining_amount(&payment);
-        if remaining <= 0 {
-            panic!("Payment fully released");
+    fn validate_ttl(config: &EscrowConfig, ttl_ledgers: u32) -> Result<(), EscrowError> {
+        if ttl_ledgers == 0 {
+            return Err(EscrowError::InvalidTtl);
         }
-        if amount > remaining {
-            panic!("Release amount exceeds remaining balance");
+        if ttl_ledgers > config.max_ttl_ledgers {
+            return Err(EscrowError::TtlExceedsMaximum);
         }
+        Ok(())
+    }
 
-        Self::transfer_from_contract(&env, &payment.merchant, amount);
-        payment.released_amount += amount;
-        if payment.released_amount == payment.amount {
-            payment.status = PaymentStatus::Released;
+    fn validate_config(config: &EscrowConfig) -> Result<(), EscrowError> {
+        if config.max_ttl_ledgers == 0 || config.max_dispute_window_ledgers == 0 {
+            return Err(EscrowError::InvalidConfig);
         }
-        env.storage()
-            .persistent()
-            .set(&DataKey::Payment(payment_id.clone()), &payment);
+        if config.max_dispute_window_ledgers > config.max_ttl_ledgers {
+            return Err(EscrowError::InvalidConfig);
+        }
+        if config.default_ttl_ledgers == 0 || config.default_ttl_ledgers > config.max_ttl_ledgers {
+            return Err(EscrowError::InvalidConfig);
+        }
+        if i128::from(config.dispute_bond_bps) > BPS_DENOMINATOR {
+            return Err(EscrowError::InvalidConfig);
+        }
+        Ok(())
+    }
 
-        PartialReleaseEvent {
-            payment_id,
-            merchant: payment.merchant,
-            amount,
+    fn validate_token_limits(limits: &TokenLimits) -> Result<(), EscrowError> {
+        if limits.min_deposit < 0 {
+            return Err(EscrowError::InvalidConfig);
         }
-        .publish(&env);
+        if let Some(max_deposit) = limits.max_deposit {
+            if max_deposit <= 0 || max_deposit < limits.min_deposit {
+                return Err(EscrowError::InvalidConfig);
+            }
+        }
+        if let Some(max_locked) = limits.max_locked {
+            if max_locked <= 0 {
+                return Err(EscrowError::InvalidConfig);
+            }
+        }
+        Ok(())
+    }
+
+    fn require_within_limits(
+        env: &Env,
+        token: &Address,
+        amount: i128,
+        payment_total: i128,
+    ) -> Result<(), EscrowError> {
+        let limits = Self::get_token_limits(env.clone(), token.clone());
+        if amount < limits.min_deposit {
+            return Err(EscrowError::DepositBelowMinimum);
+        }
+        if let Some(max_deposit) = limits.max_deposit {
+            if payment_total > max_deposit {
+                return Err(EscrowError::DepositAboveMaximum);
+            }
+        }
+        if let Some(max_locked) = limits.max_locked {
+            let locked = Self::get_locked_total(env.clone(), token.clone());
+            if locked.saturating_add(amount) > max_locked {
+                return Err(EscrowError::LockedTotalExceeded);
+            }
+        }
+        Ok(())
     }
 
-    pub fn expire(env: Env, payment_id: BytesN<32>) {
-        Self::refund(env, payment_id);
+    fn increase_locked_total(env: &Env, token: &Address, amount: i128) {
+        let locked = Self::get_locked_total(env.clone(), token.clone());
+        env.storage().instance().set(
+            &DataKey::LockedTotal(token.clone()),
+            &locked.saturating_add(amount),
+        );
     }
 
-    pub fn refund(env: Env, payment_id: BytesN<32>) {
-        let mut payment = Self::get_payment(env.clone(), payment_id.clone());
-        Self::require_expirable(&env, &payment);
+    fn decrease_locked_total(env: &Env, token: &Address, amount: i128) {
+        let locked = Self::get_locked_total(env.clone(), token.clone());
+        env.storage().instance().set(
+            &DataKey::LockedTotal(token.clone()),
+            &locked.saturating_sub(amount).max(0),
+        );
+    }
 
-        let remaining = Self::remaining_amount(&payment);
-        if remaining <= 0 {
-            panic!("Payment fully released");
+    fn validate_fee_schedule(schedule: &FeeSchedule) -> Result<(), EscrowError> {
+        if i128::from(schedule.fee_bps) > BPS_DENOMINATOR || schedule.min_fee < 0 {
+            return Err(EscrowError::InvalidConfig);
+        }
+        if let Some(max_fee) = schedule.max_fee {
+            if max_fee < schedule.min_fee {
+                return Err(EscrowError::InvalidConfig);
+            }
         }
+        Ok(())
+    }
 
-        Self::transfer_from_contract(&env, &payment.customer, remaining);
-        payment.released_amount = payment.amount;
-        payment.status = PaymentStatus::Expired;
-        env.storage()
-            .persistent()
-            .set(&DataKey::Payment(payment_id.clone()), &payment);
+    /// A merchant override replaces the global schedule outright; otherwise the
+    /// global rate is scaled by the merchant's tier multiplier, mirroring the
+    /// backend's `baseFeeRate * feeMultiplier` before the min/max clamp.
+    fn merchant_fee(env: &Env, merchant: &Address, amount: i128) -> i128 {
+        if let Some(schedule) = Self::get_merchant_fee_override(env.clone(), merchant.clone()) {
+            return Self::compute_fee(&schedule, DEFAULT_FEE_MULTIPLIER_BPS, amount);
+        }
+        let multiplier_bps = Self::get_merchant_fee_tier(env.clone(), merchant.clone())
+            .and_then(|tier| Self::get_fee_tier(env.clone(), tier))
+            .unwrap_or(DEFAULT_FEE_MULTIPLIER_BPS);
+        Self::compute_fee(&Self::get_fee_schedule(env.clone()), multiplier_bps, amount)
+    }
 
-        ExpiryEvent {
-            payment_id,
-            customer: payment.customer,
-            amount: remaining,
+    fn compute_fee(schedule: &FeeSchedule, multiplier_bps: u32, amount: i128) -> i128 {
+        let mut fee = amount
+            .saturating_mul(i128::from(schedule.fee_bps))
+            .saturating_mul(i128::from(multiplier_bps))
+            / (BPS_DENOMINATOR * BPS_DENOMINATOR);
+        if fee < schedule.min_fee {
+            fee = schedule.min_fee;
         }
-        .publish(&env);
+        if let Some(max_fee) = schedule.max_fee {
+            if fee > max_fee {
+                fee = max_fee;
+            }
+        }
+        fee.min(amount)
     }
 
-    pub fn dispute(env: Env, caller: Address, payment_id: BytesN<32>, reason: String) {
-        caller.require_auth();
+    fn require_releasable(env: &Env, payment: &PaymentEscrow) -> Result<(), EscrowError> {
+        Self::require_no_milestone_dispute(payment)?;
+        Self::require_tranche_releasable(env, payment)
+    }
 
-        let mut payment = Self::get_payment(env.clone(), payment_id.clone());
+    /// Payment-level checks for releasing a single milestone; disputes on
+    /// other milestones do not block it.
+    fn require_tranche_releasable(env: &Env, payment: &PaymentEscrow) -> Result<(), EscrowError> {
         if payment.status == PaymentStatus::Disputed {
-            panic!("Dispute already open");
+            return Err(EscrowError::DisputeOpen);
+        }
+        if payment.status == PaymentStatus::Released {
+            return Err(EscrowError::PaymentFullyReleased);
         }
-        if payment.status != PaymentStatus::Pending {
-            panic!("Payment is not pending");
+        if payment.status == PaymentStatus::Expired {
+            return Err(EscrowError::PaymentExpired);
         }
-        if caller != payment.customer && caller != payment.merchant {
-            panic!("Not payment participant");
+        if !Self::is_open(payment) {
+            return Err(EscrowError::PaymentNotPending);
         }
-        if env.ledger().sequence() > payment.dispute_window_end {
-            panic!("Dispute window expired");
+        if env.ledger().sequence() > payment.expiry {
+            return Err(EscrowError::PaymentExpired);
         }
+        Ok(())
+    }
 
-        payment.status = PaymentStatus::Disputed;
-        payment.dispute_reason = Some(reason.clone());
-        env.storage()
-            .persistent()
-            .set(&DataKey::Payment(payment_id.clone()), &payment);
+    fn require_expirable(env: &Env, payment: &PaymentEscrow) -> Result<(), EscrowError> {
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
+        }
+        if payment.status == PaymentStatus::Released {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+        if !Self::is_open(payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        Self::require_no_milestone_dispute(payment)?;
+        if env.ledger().sequence() <= payment.expiry {
+            return Err(EscrowError::PaymentNotExpired);
+        }
+        if payment.release_on_window_close {
+            return Err(EscrowError::AutoReleasePending);
+        }
+        if payment.stream_end_ledger.is_some() {
+            return Err(EscrowError::StreamActive);
+        }
+        Ok(())
+    }
 
-        DisputeOpenedEvent {
-            payment_id,
-            opened_by: caller,
-            reason,
+    fn require_refundable(payment: &PaymentEscrow) -> Result<(), EscrowError> {
+        Self::require_no_milestone_dispute(payment)?;
+        Self::require_tranche_refundable(payment)
+    }
+
+    /// Payment-level checks for refunding a single milestone; disputes on
+    /// other milestones do not block it.
+    fn require_tranche_refundable(payment: &PaymentEscrow) -> Result<(), EscrowError> {
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
         }
-        .publish(&env);
+        if payment.status == PaymentStatus::Released {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+        if !Self::is_open(payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        Ok(())
     }
 
-    pub fn resolve_dispute(env: Env, caller: Address, payment_id: BytesN<32>, winner: Address) {
-        caller.require_auth();
-        Self::require_admin(&env, &caller);
+    fn require_can_dispute(
+        env: &Env,
+        payment: &PaymentEscrow,
+        caller: &Address,
+        window_end: u32,
+    ) -> Result<(), EscrowError> {
+        if *caller != payment.customer && *caller != payment.merchant {
+            return Err(EscrowError::NotParticipant);
+        }
+        if env.ledger().sequence() > window_end {
+            return Err(EscrowError::DisputeWindowExpired);
+        }
+        Ok(())
+    }
 
-        let mut payment = Self::get_payment(env.clone(), payment_id.clone());
-        if payment.status != PaymentStatus::Disputed {
-            panic!("Dispute is not open");
+    fn require_no_milestone_dispute(payment: &PaymentEscrow) -> Result<(), EscrowError> {
+        if Self::has_milestone_dispute(payment) {
+            return Err(EscrowError::DisputeOpen);
         }
-        if winner != payment.customer && winner != payment.merchant {
-            panic!("Invalid dispute winner");
+        Ok(())
+    }
+
+    fn has_milestone_dispute(payment: &PaymentEscrow) -> bool {
+        payment
+            .milestones
+            .iter()
+            .any(|milestone| milestone.status == MilestoneStatus::Disputed)
+    }
+
+    fn build_milestones(
+        env: &Env,
+        specs: Option<Vec<MilestoneSpec>>,
+        amount: i128,
+        expiry: u32,
+    ) -> Result<Vec<Milestone>, EscrowError> {
+        let mut milestones = Vec::new(env);
+        let Some(specs) = specs else {
+            return Ok(milestones);
+        };
+        if specs.is_empty() || specs.len() > MAX_MILESTONES {
+            return Err(EscrowError::InvalidMilestones);
         }
 
-        let remaining = Self::remaining_amount(&payment);
-        if remaining <= 0 {
-            panic!("Payment fully released");
+        let mut total: i128 = 0;
+        for spec in specs.iter() {
+            if spec.amount <= 0 {
+                return Err(EscrowError::InvalidMilestones);
+            }
+            if let Some(deadline) = spec.deadline {
+                if deadline <= env.ledger().sequence() || deadline > expiry {
+                    return Err(EscrowError::InvalidMilestones);
+                }
+            }
+            total = total.saturating_add(spec.amount);
+            milestones.push_back(Milestone {
+                amount: spec.amount,
+                description_hash: spec.description_hash,
+                deadline: spec.deadline,
+                status: MilestoneStatus::Pending,
+                dispute_opened_by: None,
+                dispute_bond: 0,
+            });
+        }
+        if total != amount {
+            return Err(EscrowError::InvalidMilestones);
+        }
+        Ok(milestones)
+    }
+
+    fn load_milestone(payment: &PaymentEscrow, index: u32) -> Result<Milestone, EscrowError> {
+        payment
+            .milestones
+            .get(index)
+            .ok_or(EscrowError::MilestoneNotFound)
+    }
+
+    fn settle_milestones(payment: &mut PaymentEscrow, status: MilestoneStatus) {
+        for index in 0..payment.milestones.len() {
+            let mut milestone = payment.milestones.get_unchecked(index);
+            if milestone.status == MilestoneStatus::Pending {
+                milestone.status = status.clone();
+                payment.milestones.set(index, milestone);
+            }
+        }
+    }
+
+    /// Vesting is frozen while a dispute is open: withdrawals and
+    /// cancellation wait for the arbiter, who settles the whole remainder.
+    fn require_open_stream(env: &Env, payment: &PaymentEscrow) -> Result<i128, EscrowError> {
+        let end_ledger = payment.stream_end_ledger.ok_or(EscrowError::NotStream)?;
+        if payment.status == PaymentStatus::Disputed {
+            return Err(EscrowError::DisputeOpen);
         }
+        if payment.status == PaymentStatus::Released {
+            return Err(EscrowError::PaymentFullyReleased);
+        }
+        if !Self::is_open(payment) {
+            return Err(EscrowError::PaymentNotPending);
+        }
+        Ok(Self::vested_amount(env, payment, end_ledger))
+    }
 
-        Self::transfer_from_contract(&env, &winner, remaining);
-        payment.status = if winner == payment.merchant {
+    fn vested_amount(env: &Env, payment: &PaymentEscrow, end_ledger: u32) -> i128 {
+        let now = env.ledger().sequence().min(end_ledger);
+        let elapsed = now.saturating_sub(payment.created_ledger);
+        let duration = end_ledger - payment.created_ledger;
+        payment.amount.saturating_mul(i128::from(elapsed)) / i128::from(duration)
+    }
+
+    fn settled_status(payment: &PaymentEscrow) -> PaymentStatus {
+        if Self::remaining_amount(payment) > 0 {
+            if payment.refunded_amount > 0 {
+                PaymentStatus::PartiallyRefunded
+            } else if payment
+                .target_amount
+                .is_some_and(|target| payment.amount >= target)
+            {
+                PaymentStatus::Funded
+            } else {
+                PaymentStatus::Pending
+            }
+        } else if payment.released_amount > 0 {
             PaymentStatus::Released
         } else {
-            PaymentStatus::Expired
-        };
-        payment.released_amount = payment.amount;
-        env.storage()
-            .persistent()
-            .set(&DataKey::Payment(payment_id.clone()), &payment);
-
-        DisputeResolvedEvent {
-            payment_id,
-            winner,
-            amount: remaining,
+            PaymentStatus::Refunded
         }
-        .publish(&env);
     }
 
-    pub fn get_payment(env: Env, payment_id: BytesN<32>) -> PaymentEscrow {
+    /// A payment still holding funds that can be released, refunded or disputed.
+    fn is_open(payment: &PaymentEscrow) -> bool {
+        matches!(
+            payment.status,
+            PaymentStatus::Pending | PaymentStatus::PartiallyRefunded | PaymentStatus::Funded
+        )
+    }
+
+    fn payment_exists(env: &Env, payment_id: &BytesN<32>) -> bool {
         env.storage()
             .persistent()
-            .get(&DataKey::Payment(payment_id))
-            .expect("Payment not found")
+            .has(&DataKey::PaymentV2(payment_id.clone()))
+            || env
+                .storage()
+                .persistent()
+                .has(&DataKey::Payment(payment_id.clone()))
     }
 
-    pub fn get_balance(env: Env, payment_id: BytesN<32>) -> i128 {
-        let payment = Self::get_payment(env, payment_id);
-        Self::remaining_amount(&payment)
+    fn load_payment(env: &Env, payment_id: &BytesN<32>) -> Result<PaymentEscrow, EscrowError> {
+        if let Some(payment) = env
+            .storage()
+            .persistent()
+            .get(&DataKey::PaymentV2(payment_id.clone()))
+        {
+            return Ok(payment);
+        }
+        Self::migrate_legacy_payment(env, payment_id).ok_or(EscrowError::PaymentNotFound)
     }
 
-    pub fn get_expiry(env: Env, payment_id: BytesN<32>) -> u32 {
-        Self::get_payment(env, payment_id).expiry
+    fn migrate_legacy_payment(env: &Env, payment_id: &BytesN<32>) -> Option<PaymentEscrow> {
+        let legacy_key = DataKey::Payment(payment_id.clone());
+        let legacy: PaymentEscrowV1 = env.storage().persistent().get(&legacy_key)?;
+        let payment = legacy.into_current(env, Self::get_usdc_token(env.clone()));
+
+        Self::save_payment(env, &payment);
+        env.storage().persistent().remove(&legacy_key);
+        Self::increase_locked_total(env, &payment.token, Self::remaining_amount(&payment));
+        Some(payment)
     }
 
-    pub fn get_admin(env: Env) -> Address {
-        env.storage().instance().get(&DataKey::Admin).unwrap()
+    fn save_payment(env: &Env, payment: &PaymentEscrow) {
+        env.storage()
+            .persistent()
+            .set(&DataKey::PaymentV2(payment.payment_id.clone()), payment);
     }
 
-    pub fn get_usdc_token(env: Env) -> Address {
-        env.storage().instance().get(&DataKey::UsdcToken).unwrap()
+    fn remaining_amount(payment: &PaymentEscrow) -> i128 {
+        payment
+            .amount
+            .saturating_sub(payment.released_amount)
+            .saturating_sub(payment.refunded_amount)
     }
 
-    pub fn get_default_ttl_ledgers(env: Env) -> u32 {
-        env.storage()
-            .instance()
-            .get(&DataKey::DefaultTtlLedgers)
-            .unwrap()
+    fn refund_recipient(payment: &PaymentEscrow) -> Address {
+        payment
+            .refund_to
+            .clone()
+            .unwrap_or_else(|| payment.customer.clone())
     }
 
-    pub fn get_max_dispute_window_ledgers(_env: Env) -> u32 {
-        MAX_DISPUTE_WINDOW_LEDGERS
+    fn pay_out(env: &Env, payment: &PaymentEscrow, recipient: &Address, amount: i128) {
+        Self::decrease_locked_total(env, &payment.token, amount);
+        Self::transfer_from_contract(env, &payment.token, recipient, amount);
     }
 
-    pub fn get_max_ttl_ledgers(_env: Env) -> u32 {
-        MAX_TTL_LEDGERS
+    /// Returns the bond to the opener when the ruling goes their way;
+    /// otherwise it is forfeited to the winning counterparty or the fee pool.
+    fn collect_dispute_bond(env: &Env, token: &Address, caller: &Address, disputed: i128) -> i128 {
+        let bond = disputed
+            .saturating_mul(i128::from(Self::get_config(env.clone()).dispute_bond_bps))
+            / BPS_DENOMINATOR;
+        if bond > 0 {
+            token::Client::new(env, token).transfer(caller, env.current_contract_address(), &bond);
+        }
+        bond
     }
 
-    fn require_admin(env: &Env, caller: &Address) {
-        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
-        if caller != &admin {
-            panic!("Not admin");
+    fn settle_dispute_bond(
+        env: &Env,
+        token: &Address,
+        opened_by: &Option<Address>,
+        bond: i128,
+        winner: &Address,
+    ) -> bool {
+        if bond <= 0 {
+            return false;
+        }
+        if opened_by.as_ref() == Some(winner) {
+            Self::transfer_from_contract(env, token, winner, bond);
+            return true;
         }
+        if Self::get_config(env.clone()).forfeit_bond_to_fees {
+            let accrued = Self::get_accrued_fees(env.clone(), token.clone());
+            env.storage().instance().set(
+                &DataKey::AccruedFees(token.clone()),
+                &accrued.saturating_add(bond),
+            );
+        } else {
+            Self::transfer_from_contract(env, token, winner, bond);
+        }
+        false
     }
 
-    fn require_releasable(env: &Env, payment: &PaymentEscrow) {
-        if payment.status == PaymentStatus::Disputed {
-            panic!("Dispute is open");
+    fn pay_merchant(env: &Env, payment: &PaymentEscrow, amount: i128) -> (i128, Vec<Payout>) {
+        let fee = Self::merchant_fee(env, &payment.merchant, amount);
+        Self::decrease_locked_total(env, &payment.token, amount);
+        if fee > 0 {
+            let accrued = Self::get_accrued_fees(env.clone(), payment.token.clone());
+            env.storage().instance().set(
+                &DataKey::AccruedFees(payment.token.clone()),
+                &accrued.saturating_add(fee),
+            );
         }
-        if payment.status == PaymentStatus::Released {
-            panic!("Payment fully released");
+
+        let payouts = Self::split_payout(env, payment, amount - fee);
+        for payout in payouts.iter() {
+            if payout.amount > 0 {
+                Self::transfer_from_contract(env, &payment.token, &payout.recipient, payout.amount);
+            }
         }
-        if payment.status == PaymentStatus::Expired {
-            panic!("Payment expired");
+        (fee, payouts)
+    }
+
+    /// Divides the net payout by payee share, rounding down; the rounding
+    /// remainder goes to the first payee.
+    fn split_payout(env: &Env, payment: &PaymentEscrow, net: i128) -> Vec<Payout> {
+        if payment.payees.is_empty() {
+            return Self::single_payout(env, &payment.merchant, net);
         }
-        if env.ledger().sequence() > payment.expiry {
-            panic!("Payment expired");
+
+        let mut payouts = Vec::new(env);
+        let mut distributed: i128 = 0;
+        for payee in payment.payees.iter() {
+            let amount = net.saturating_mul(i128::from(payee.share_bps)) / BPS_DENOMINATOR;
+            distributed += amount;
+            payouts.push_back(Payout {
+                recipient: payee.recipient,
+                amount,
+            });
         }
+        let mut first = payouts.get_unchecked(0);
+        first.amount += net - distributed;
+        payouts.set(0, first);
+        payouts
     }
 
-    fn require_expirable(env: &Env, payment: &PaymentEscrow) {
-        if payment.status == PaymentStatus::Disputed {
-            panic!("Dispute is open");
+    fn single_payout(env: &Env, recipient: &Address, amount: i128) -> Vec<Payout> {
+        Vec::from_array(
+            env,
+            [Payout {
+                recipient: recipient.clone(),
+                amount,
+            }],
+        )
+    }
+
+    fn build_metadata(
+        env: &Env,
+        external_ref: Option<String>,
+        order_hash: Option<BytesN<32>>,
+        memo: Option<String>,
+    ) -> Result<Option<PaymentMetadata>, EscrowError> {
+        if external_ref.is_none() && order_hash.is_none() && memo.is_none() {
+            return Ok(None);
         }
-        if payment.status == PaymentStatus::Released {
-            panic!("Payment fully released");
+        if let Some(external_ref) = &external_ref {
+            if external_ref.is_empty() || external_ref.len() > MAX_EXTERNAL_REF_LEN {
+                return Err(EscrowError::InvalidMetadata);
+            }
+            if env
+                .storage()
+                .persistent()
+                .has(&DataKey::PaymentReference(external_ref.clone()))
+            {
+                return Err(EscrowError::ReferenceAlreadyUsed);
+            }
         }
-        if payment.status != PaymentStatus::Pending {
-            panic!("Payment is not pending");
+        if matches!(&memo, Some(memo) if memo.len() > MAX_MEMO_LEN) {
+            return Err(EscrowError::InvalidMetadata);
         }
-        if env.ledger().sequence() <= payment.expiry {
-            panic!("Payment has not expired");
+        Ok(Some(PaymentMetadata {
+            external_ref,
+            order_hash,
+            memo,
+        }))
+    }
+
+    fn build_payees(env: &Env, payees: Option<Vec<Payee>>) -> Result<Vec<Payee>, EscrowError> {
+        let Some(payees) = payees else {
+            return Ok(Vec::new(env));
+        };
+        if payees.is_empty() || payees.len() > MAX_PAYEES {
+            return Err(EscrowError::InvalidPayees);
+        }
+        let mut total: u32 = 0;
+        for payee in payees.iter() {
+            if payee.share_bps == 0 {
+                return Err(EscrowError::InvalidPayees);
+            }
+            total = total.saturating_add(payee.share_bps);
+        }
+        if i128::from(total) != BPS_DENOMINATOR {
+            return Err(EscrowError::InvalidPayees);
         }
+        Ok(payees)
     }
 
-    fn remaining_amount(payment: &PaymentEscrow) -> i128 {
-        payment.amount.saturating_sub(payment.released_amount)
+    /// Every subscription a customer holds on a token shares their single
+    /// allowance for this contract, so each one only adds or removes its own
+    /// unspent budget. The allowance is kept live for as long as the network
+    /// allows; its amount never exceeds what active subscriptions can still pull.
+    fn adjust_subscription_allowance(env: &Env, token: &Address, customer: &Address, delta: i128) {
+        let contract = env.current_contract_address();
+        let token_client = token::Client::new(env, token);
+        let allowance = token_client
+            .allowance(customer, &contract)
+            .saturating_add(delta)
+            .max(0);
+        token_client.approve(
+            customer,
+            &contract,
+            &allowance,
+            &env.ledger().max_live_until_ledger(),
+        );
+    }
+
+    fn transfer_to_contract(env: &Env, token: &Address, payer: &Address, amount: i128) {
+        let token_client = token::Client::new(env, token);
+        token_client.transfer(payer, env.current_contract_address(), &amount);
     }
 
-    fn transfer_from_contract(env: &Env, recipient: &Address, amount: i128) {
-        let usdc_token: Address = env.storage().instance().get(&DataKey::UsdcToken).unwrap();
-        let token_client = token::Client::new(env, &usdc_token);
+    fn transfer_from_contract(env: &Env, token: &Address, recipient: &Address, amount: i128) {
+        let token_client = token::Client::new(env, token);
         token_client.transfer(&env.current_contract_address(), recipient, &amount);
     }
 }
@@ -1,8 +1,14 @@
 #![cfg(test)]
 
-use crate::{PaymentEscrowContract, PaymentEscrowContractClient, PaymentStatus};
+use crate::{
+    DataKey, DepositOptions, EscrowConfig, EscrowError, ExpiryEvent, FeeSchedule, MerchantQuote,
+    MilestoneSpec, MilestoneStatus, PauseScope, PauseState, Payee, PaymentEscrowContract,
+    PaymentEscrowContractClient, PaymentStatus, RefundEvent, Role, SubscriptionTerms, TokenLimits,
+};
+use ed25519_dalek::{Signer, SigningKey};
 use soroban_sdk::{
-    testutils::Address as _, testutils::Ledger, token, Address, BytesN, Env, String,
+    testutils::Address as _, testutils::Events, testutils::Ledger, token, vec, xdr::ToXdr, Address,
+    Bytes, BytesN, Env, Event, String,
 };
 
 const DEFAULT_PAYMENT_TTL: u32 = 100;
@@ -40,6 +46,34 @@ fn make_id(env: &Env, seed: u8) -> BytesN<32> {
     BytesN::from_array(env, &[seed; 32])
 }
 
+fn ttl_options(ttl_ledgers: u32) -> Option<DepositOptions> {
+    Some(DepositOptions {
+        ttl_ledgers: Some(ttl_ledgers),
+        ..Default::default()
+    })
+}
+
+fn deposit_with_options(
+    client: &PaymentEscrowContractClient<'_>,
+    customer: &Address,
+    payment_id: &BytesN<32>,
+    merchant: &Address,
+    amount: i128,
+    options: DepositOptions,
+) -> Result<(), EscrowError> {
+    client
+        .try_deposit(
+            customer,
+            payment_id,
+            merchant,
+            &client.get_usdc_token(),
+            &amount,
+            &Some(options),
+        )
+        .map(|_| ())
+        .map_err(|err| err.unwrap())
+}
+
 fn deposit_default_ttl(
     client: &PaymentEscrowContractClient<'_>,
     customer: &Address,
@@ -51,8 +85,9 @@ fn deposit_default_ttl(
         customer,
         payment_id,
         merchant,
+        &client.get_usdc_token(),
         &amount,
-        &DEFAULT_PAYMENT_TTL,
+        &None,
     );
 }
 
@@ -74,12 +109,14 @@ fn test_deposit_happy_path() {
         &customer,
         &payment_id,
         &merchant,
+        &usdc,
         &250_000_000i128,
-        &DEFAULT_PAYMENT_TTL,
+        &ttl_options(DEFAULT_PAYMENT_TTL),
     );
     let payment = client.get_payment(&payment_id);
 
     assert_eq!(result, payment_id);
+    assert_eq!(payment.token, usdc.clone());
     assert_eq!(payment.amount, 250_000_000);
     assert_eq!(payment.released_amount, 0);
     assert_eq!(payment.customer, customer.clone());
@@ -97,404 +134,1039 @@ fn test_deposit_happy_path() {
 }
 
 #[test]
-#[should_panic(expected = "Payment ID already exists")]
 fn test_deposit_duplicate_payment_id() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &payment_id,
+            &merchant,
+            &usdc,
+            &250_000_000i128,
+            &ttl_options(DEFAULT_PAYMENT_TTL)
+        ),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
 }
 
 #[test]
-#[should_panic(expected = "Amount must be > 0")]
 fn test_deposit_zero_amount() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
 
-    client.deposit(
-        &customer,
-        &make_id(&env, 1),
-        &merchant,
-        &0i128,
-        &DEFAULT_PAYMENT_TTL,
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 1),
+            &merchant,
+            &usdc,
+            &0i128,
+            &ttl_options(DEFAULT_PAYMENT_TTL)
+        ),
+        Err(Ok(EscrowError::InvalidAmount))
     );
 }
 
 #[test]
-#[should_panic(expected = "TTL must be > 0")]
 fn test_deposit_zero_ttl() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
 
-    client.deposit(
-        &customer,
-        &make_id(&env, 1),
-        &merchant,
-        &250_000_000i128,
-        &0u32,
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 1),
+            &merchant,
+            &usdc,
+            &250_000_000i128,
+            &ttl_options(0)
+        ),
+        Err(Ok(EscrowError::InvalidTtl))
     );
 }
 
 #[test]
-#[should_panic(expected = "TTL exceeds maximum")]
 fn test_deposit_excessive_ttl() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
     let ttl = client.get_max_ttl_ledgers() + 1;
 
-    client.deposit(
-        &customer,
-        &make_id(&env, 1),
-        &merchant,
-        &250_000_000i128,
-        &ttl,
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 1),
+            &merchant,
+            &usdc,
+            &250_000_000i128,
+            &ttl_options(ttl)
+        ),
+        Err(Ok(EscrowError::TtlExceedsMaximum))
     );
 }
 
 #[test]
 fn test_get_expiry_with_short_ttl() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
-    client.deposit(&customer, &payment_id, &merchant, &250_000_000i128, &5u32);
+    client.deposit(
+        &customer,
+        &payment_id,
+        &merchant,
+        &usdc,
+        &250_000_000i128,
+        &ttl_options(5),
+    );
 
     assert_eq!(client.get_expiry(&payment_id), 15);
 }
 
 #[test]
 fn test_get_expiry_with_long_ttl() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     client.deposit(
         &customer,
         &payment_id,
         &merchant,
+        &usdc,
         &250_000_000i128,
-        &2_000u32,
+        &ttl_options(2_000),
     );
 
     assert_eq!(client.get_expiry(&payment_id), 2_010);
 }
 
 #[test]
-fn test_release_happy_path() {
-    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
+fn test_deposit_without_ttl_uses_default() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let first = make_id(&env, 1);
+    let second = make_id(&env, 2);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release(&admin, &payment_id);
+    client.deposit(&customer, &first, &merchant, &usdc, &250_000_000i128, &None);
+    assert_eq!(client.get_expiry(&first), 10 + DEFAULT_PAYMENT_TTL);
 
-    let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Released);
-    assert_eq!(payment.released_amount, 250_000_000);
-    assert_eq!(client.get_balance(&payment_id), 0);
+    client.set_default_ttl_ledgers(&admin, &500u32);
+    assert_eq!(client.get_default_ttl_ledgers(), 500);
 
-    let token_client = token::Client::new(&env, &usdc);
-    assert_eq!(token_client.balance(&merchant), 250_000_000);
-    assert_eq!(token_client.balance(&contract_id), 0);
+    client.deposit(
+        &customer,
+        &second,
+        &merchant,
+        &usdc,
+        &250_000_000i128,
+        &None,
+    );
+    assert_eq!(client.get_expiry(&second), 510);
 }
 
 #[test]
-fn test_partial_release_multiple_steps() {
-    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
-
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release_partial(&admin, &payment_id, &100_000_000i128);
-
-    let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Pending);
-    assert_eq!(payment.released_amount, 100_000_000);
-    assert_eq!(client.get_balance(&payment_id), 150_000_000);
-
-    client.release_partial(&admin, &payment_id, &150_000_000i128);
+fn test_set_default_ttl_validation() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let random = Address::generate(&env);
+    let max_ttl = client.get_max_ttl_ledgers();
 
-    let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Released);
-    assert_eq!(payment.released_amount, 250_000_000);
-    assert_eq!(client.get_balance(&payment_id), 0);
+    assert_eq!(
+        client.try_set_default_ttl_ledgers(&random, &500u32),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+    assert_eq!(
+        client.try_set_default_ttl_ledgers(&admin, &0u32),
+        Err(Ok(EscrowError::InvalidTtl))
+    );
+    assert_eq!(
+        client.try_set_default_ttl_ledgers(&admin, &(max_ttl + 1)),
+        Err(Ok(EscrowError::TtlExceedsMaximum))
+    );
 
-    let token_client = token::Client::new(&env, &usdc);
-    assert_eq!(token_client.balance(&merchant), 250_000_000);
-    assert_eq!(token_client.balance(&contract_id), 0);
+    client.set_default_ttl_ledgers(&admin, &max_ttl);
+    assert_eq!(client.get_default_ttl_ledgers(), max_ttl);
 }
 
 #[test]
-#[should_panic(expected = "Release amount exceeds remaining balance")]
-fn test_partial_release_prevents_over_release() {
-    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
+fn test_default_config() {
+    let (_env, client, _contract_id, _admin, _customer, _merchant, _usdc) = setup_env();
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release_partial(&admin, &payment_id, &200_000_000i128);
-    client.release_partial(&admin, &payment_id, &100_000_000i128);
+    assert_eq!(
+        client.get_config(),
+        EscrowConfig {
+            default_ttl_ledgers: DEFAULT_PAYMENT_TTL,
+            max_ttl_ledgers: 518_400,
+            max_dispute_window_ledgers: 51_840,
+            dispute_bond_bps: 0,
+            forfeit_bond_to_fees: false,
+            extension_requires_merchant: false,
+        }
+    );
+    assert_eq!(client.get_max_ttl_ledgers(), 518_400);
+    assert_eq!(client.get_max_dispute_window_ledgers(), 51_840);
 }
 
 #[test]
-fn test_refund_returns_remaining_balance_after_partial_release() {
-    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+fn test_set_config_changes_deposit_limits() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
+    let config = EscrowConfig {
+        default_ttl_ledgers: 50,
+        max_ttl_ledgers: 1_000,
+        max_dispute_window_ledgers: 20,
+        dispute_bond_bps: 0,
+        forfeit_bond_to_fees: false,
+        extension_requires_merchant: false,
+    };
+
+    client.set_config(&admin, &config);
+    assert_eq!(client.get_config(), config);
+    assert_eq!(client.get_max_ttl_ledgers(), 1_000);
+    assert_eq!(client.get_max_dispute_window_ledgers(), 20);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release_partial(&admin, &payment_id, &100_000_000i128);
-    env.ledger().set_sequence_number(111);
-    client.refund(&payment_id);
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &payment_id,
+            &merchant,
+            &usdc,
+            &250_000_000i128,
+            &ttl_options(1_001)
+        ),
+        Err(Ok(EscrowError::TtlExceedsMaximum))
+    );
 
+    client.deposit(
+        &customer,
+        &payment_id,
+        &merchant,
+        &usdc,
+        &250_000_000i128,
+        &None,
+    );
     let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Expired);
-    assert_eq!(client.get_balance(&payment_id), 0);
-
-    let token_client = token::Client::new(&env, &usdc);
-    assert_eq!(token_client.balance(&merchant), 100_000_000);
-    assert_eq!(token_client.balance(&customer), 900_000_000);
-    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(payment.expiry, 60);
+    assert_eq!(payment.dispute_window_end, 30);
 }
 
 #[test]
-#[should_panic(expected = "Not admin")]
-fn test_release_unauthorized() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
+fn test_set_config_validation() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
     let random = Address::generate(&env);
+    let valid = EscrowConfig {
+        default_ttl_ledgers: 50,
+        max_ttl_ledgers: 1_000,
+        max_dispute_window_ledgers: 20,
+        dispute_bond_bps: 0,
+        forfeit_bond_to_fees: false,
+        extension_requires_merchant: false,
+    };
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release(&random, &payment_id);
+    assert_eq!(
+        client.try_set_config(&random, &valid),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+    for invalid in [
+        EscrowConfig {
+            max_dispute_window_ledgers: 1_001,
+            ..valid.clone()
+        },
+        EscrowConfig {
+            default_ttl_ledgers: 1_001,
+            ..valid.clone()
+        },
+        EscrowConfig {
+            default_ttl_ledgers: 0,
+            ..valid.clone()
+        },
+        EscrowConfig {
+            max_dispute_window_ledgers: 0,
+            ..valid.clone()
+        },
+        EscrowConfig {
+            dispute_bond_bps: 10_001,
+            ..valid.clone()
+        },
+    ] {
+        assert_eq!(
+            client.try_set_config(&admin, &invalid),
+            Err(Ok(EscrowError::InvalidConfig))
+        );
+    }
+}
+
+fn register_token(env: &Env, holder: &Address) -> Address {
+    let asset_contract = env.register_stellar_asset_contract_v2(Address::generate(env));
+    let token = asset_contract.address();
+    token::StellarAssetClient::new(env, &token).mint(holder, &1_000_000_000i128);
+    token
 }
 
 #[test]
-#[should_panic(expected = "Not admin")]
-fn test_partial_release_unauthorized() {
+fn test_allowed_tokens_default_to_usdc() {
+    let (env, client, _contract_id, _admin, _customer, _merchant, usdc) = setup_env();
+
+    assert_eq!(client.get_allowed_tokens(), vec![&env, usdc.clone()]);
+    assert!(client.is_token_allowed(&usdc));
+    assert!(!client.is_token_allowed(&Address::generate(&env)));
+}
+
+#[test]
+fn test_deposit_unlisted_token_rejected() {
     let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
-    let random = Address::generate(&env);
+    let eurc = register_token(&env, &customer);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release_partial(&random, &payment_id, &10_000_000i128);
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 1),
+            &merchant,
+            &eurc,
+            &250_000_000i128,
+            &None
+        ),
+        Err(Ok(EscrowError::TokenNotAllowed))
+    );
 }
 
 #[test]
-#[should_panic(expected = "Payment not found")]
-fn test_release_invalid_payment_id() {
-    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+fn test_multi_token_release_and_refund() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let eurc = register_token(&env, &customer);
+    let usdc_payment = make_id(&env, 1);
+    let eurc_payment = make_id(&env, 2);
+
+    client.allow_token(&admin, &eurc);
+    assert_eq!(
+        client.get_allowed_tokens(),
+        vec![&env, usdc.clone(), eurc.clone()]
+    );
+
+    client.deposit(
+        &customer,
+        &usdc_payment,
+        &merchant,
+        &usdc,
+        &100_000_000i128,
+        &None,
+    );
+    client.deposit(
+        &customer,
+        &eurc_payment,
+        &merchant,
+        &eurc,
+        &40_000_000i128,
+        &None,
+    );
+    assert_eq!(client.get_payment(&eurc_payment).token, eurc);
 
-    client.release(&admin, &make_id(&env, 99));
+    client.release(&admin, &eurc_payment);
+    env.ledger().set_sequence_number(111);
+    client.refund(&usdc_payment);
+
+    let usdc_client = token::Client::new(&env, &usdc);
+    let eurc_client = token::Client::new(&env, &eurc);
+    assert_eq!(eurc_client.balance(&merchant), 40_000_000);
+    assert_eq!(eurc_client.balance(&contract_id), 0);
+    assert_eq!(usdc_client.balance(&customer), 1_000_000_000);
+    assert_eq!(usdc_client.balance(&merchant), 0);
 }
 
 #[test]
-#[should_panic(expected = "Payment expired")]
-fn test_release_expired_payment() {
-    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+fn test_disallow_token_keeps_existing_payments() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let random = Address::generate(&env);
+    let eurc = register_token(&env, &customer);
     let payment_id = make_id(&env, 1);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    env.ledger().set_sequence_number(111);
+    assert_eq!(
+        client.try_allow_token(&random, &eurc),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+    client.allow_token(&admin, &eurc);
+    client.deposit(
+        &customer,
+        &payment_id,
+        &merchant,
+        &eurc,
+        &40_000_000i128,
+        &None,
+    );
+
+    client.disallow_token(&admin, &eurc);
+    assert!(!client.is_token_allowed(&eurc));
+    assert_eq!(client.get_usdc_token(), usdc);
+    assert_eq!(
+        client.try_disallow_token(&admin, &eurc),
+        Err(Ok(EscrowError::TokenNotAllowed))
+    );
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 2),
+            &merchant,
+            &eurc,
+            &1i128,
+            &None
+        ),
+        Err(Ok(EscrowError::TokenNotAllowed))
+    );
 
     client.release(&admin, &payment_id);
+    assert_eq!(
+        token::Client::new(&env, &eurc).balance(&merchant),
+        40_000_000
+    );
 }
 
 #[test]
-#[should_panic(expected = "Payment expired")]
-fn test_partial_release_expired_payment() {
-    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
+fn test_locked_total_tracks_escrowed_funds() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let first = make_id(&env, 1);
+    let second = make_id(&env, 2);
+    let third = make_id(&env, 3);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    deposit_default_ttl(&client, &customer, &first, &merchant, 100_000_000i128);
+    deposit_default_ttl(&client, &customer, &second, &merchant, 200_000_000i128);
+    deposit_default_ttl(&client, &customer, &third, &merchant, 300_000_000i128);
+    assert_eq!(client.get_locked_total(&usdc), 600_000_000);
+
+    client.release_partial(&admin, &first, &40_000_000i128);
+    assert_eq!(client.get_locked_total(&usdc), 560_000_000);
+
+    client.dispute(&customer, &second, &String::from_str(&env, "investigate"));
+    client.resolve_dispute(&admin, &second, &customer);
+    assert_eq!(client.get_locked_total(&usdc), 360_000_000);
+
+    client.release(&admin, &third);
     env.ledger().set_sequence_number(111);
+    client.refund(&first);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+}
 
-    client.release_partial(&admin, &payment_id, &10_000_000i128);
+#[test]
+fn test_deposit_min_and_max() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let limits = TokenLimits {
+        min_deposit: 1_000,
+        max_deposit: Some(500_000_000),
+        max_locked: None,
+    };
+
+    client.set_token_limits(&admin, &usdc, &limits);
+    assert_eq!(client.get_token_limits(&usdc), limits);
+
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 1),
+            &merchant,
+            &usdc,
+            &999i128,
+            &None
+        ),
+        Err(Ok(EscrowError::DepositBelowMinimum))
+    );
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 1),
+            &merchant,
+            &usdc,
+            &500_000_001i128,
+            &None
+        ),
+        Err(Ok(EscrowError::DepositAboveMaximum))
+    );
+    client.deposit(
+        &customer,
+        &make_id(&env, 1),
+        &merchant,
+        &usdc,
+        &1_000i128,
+        &None,
+    );
+    client.deposit(
+        &customer,
+        &make_id(&env, 2),
+        &merchant,
+        &usdc,
+        &500_000_000i128,
+        &None,
+    );
 }
 
 #[test]
-#[should_panic(expected = "Payment fully released")]
-fn test_release_already_settled_payment() {
-    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
+fn test_deposit_locked_total_cap() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let first = make_id(&env, 1);
+
+    client.set_token_limits(
+        &admin,
+        &usdc,
+        &TokenLimits {
+            min_deposit: 0,
+            max_deposit: None,
+            max_locked: Some(300_000_000),
+        },
+    );
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release(&admin, &payment_id);
-    client.release(&admin, &payment_id);
+    deposit_default_ttl(&client, &customer, &first, &merchant, 200_000_000i128);
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 2),
+            &merchant,
+            &usdc,
+            &100_000_001i128,
+            &None
+        ),
+        Err(Ok(EscrowError::LockedTotalExceeded))
+    );
+
+    client.release(&admin, &first);
+    deposit_default_ttl(
+        &client,
+        &customer,
+        &make_id(&env, 2),
+        &merchant,
+        300_000_000i128,
+    );
+    assert_eq!(client.get_locked_total(&usdc), 300_000_000);
 }
 
 #[test]
-#[should_panic(expected = "Release amount must be > 0")]
-fn test_partial_release_zero_amount() {
-    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
+fn test_set_token_limits_validation() {
+    let (env, client, _contract_id, admin, _customer, _merchant, usdc) = setup_env();
+    let random = Address::generate(&env);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.release_partial(&admin, &payment_id, &0i128);
+    assert_eq!(
+        client.try_set_token_limits(&random, &usdc, &TokenLimits::default()),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+    for invalid in [
+        TokenLimits {
+            min_deposit: -1,
+            max_deposit: None,
+            max_locked: None,
+        },
+        TokenLimits {
+            min_deposit: 100,
+            max_deposit: Some(99),
+            max_locked: None,
+        },
+        TokenLimits {
+            min_deposit: 0,
+            max_deposit: None,
+            max_locked: Some(0),
+        },
+    ] {
+        assert_eq!(
+            client.try_set_token_limits(&admin, &usdc, &invalid),
+            Err(Ok(EscrowError::InvalidConfig))
+        );
+    }
 }
 
 #[test]
-#[should_panic(expected = "Payment fully released")]
-fn test_partial_release_fully_released_payment() {
-    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+fn test_top_up_until_funded() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100_000_000,
+        DepositOptions {
+            target_amount: Some(250_000_000),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(payment.target_amount, Some(250_000_000));
+
+    client.top_up(&payment_id, &100_000_000i128);
+    assert_eq!(
+        client.get_payment(&payment_id).status,
+        PaymentStatus::Pending
+    );
+    assert_eq!(
+        client.try_top_up(&payment_id, &100_000_000i128),
+        Err(Ok(EscrowError::TargetExceeded))
+    );
+    client.top_up(&payment_id, &50_000_000i128);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Funded);
+    assert_eq!(payment.amount, 250_000_000);
+    assert_eq!(client.get_balance(&payment_id), 250_000_000);
+    assert_eq!(client.get_locked_total(&usdc), 250_000_000);
+
+    client.release_partial(&admin, &payment_id, &50_000_000i128);
+    assert_eq!(
+        client.get_payment(&payment_id).status,
+        PaymentStatus::Funded
+    );
     client.release(&admin, &payment_id);
-    client.release_partial(&admin, &payment_id, &1i128);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 250_000_000);
+    assert_eq!(token_client.balance(&customer), 750_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
 }
 
 #[test]
-fn test_expire_refunds_customer() {
-    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+fn test_top_up_without_target() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    env.ledger().set_sequence_number(111);
-    client.refund(&payment_id);
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 100_000_000i128);
+    client.top_up(&payment_id, &25_000_000i128);
 
     let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Expired);
-    assert_eq!(client.get_balance(&payment_id), 0);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(payment.amount, 125_000_000);
+    assert_eq!(client.get_locked_total(&usdc), 125_000_000);
 
+    env.ledger().set_sequence_number(111);
+    client.refund(&payment_id);
     let token_client = token::Client::new(&env, &usdc);
     assert_eq!(token_client.balance(&customer), 1_000_000_000);
-    assert_eq!(token_client.balance(&contract_id), 0);
 }
 
 #[test]
-#[should_panic(expected = "Payment has not expired")]
-fn test_expire_before_ttl() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+fn test_top_up_validation() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.expire(&payment_id);
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            100,
+            DepositOptions {
+                target_amount: Some(99),
+                ..Default::default()
+            }
+        ),
+        Err(EscrowError::InvalidTargetAmount)
+    );
+    for options in [
+        DepositOptions {
+            target_amount: Some(200),
+            milestones: Some(vec![&env, milestone_spec(&env, 100, None)]),
+            ..Default::default()
+        },
+        DepositOptions {
+            target_amount: Some(200),
+            stream_duration_ledgers: Some(50),
+            ..Default::default()
+        },
+    ] {
+        assert_eq!(
+            deposit_with_options(&client, &customer, &payment_id, &merchant, 100, options),
+            Err(EscrowError::InvalidTargetAmount)
+        );
+    }
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100,
+        DepositOptions {
+            target_amount: Some(100),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        client.get_payment(&payment_id).status,
+        PaymentStatus::Funded
+    );
+    assert_eq!(
+        client.try_top_up(&payment_id, &1i128),
+        Err(Ok(EscrowError::TargetExceeded))
+    );
+
+    let open_id = make_id(&env, 2);
+    deposit_default_ttl(&client, &customer, &open_id, &merchant, 100i128);
+    assert_eq!(
+        client.try_top_up(&open_id, &0i128),
+        Err(Ok(EscrowError::InvalidAmount))
+    );
+    client.set_token_limits(
+        &admin,
+        &usdc,
+        &TokenLimits {
+            min_deposit: 10,
+            max_deposit: Some(150),
+            max_locked: None,
+        },
+    );
+    assert_eq!(
+        client.try_top_up(&open_id, &5i128),
+        Err(Ok(EscrowError::DepositBelowMinimum))
+    );
+    assert_eq!(
+        client.try_top_up(&open_id, &60i128),
+        Err(Ok(EscrowError::DepositAboveMaximum))
+    );
+
+    client.pause(&admin, &PauseScope::Deposits);
+    assert_eq!(
+        client.try_top_up(&open_id, &10i128),
+        Err(Ok(EscrowError::Paused))
+    );
+    client.unpause(&admin, &PauseScope::Deposits);
+
+    client.dispute(&customer, &open_id, &String::from_str(&env, "wrong item"));
+    assert_eq!(
+        client.try_top_up(&open_id, &10i128),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+}
+
+fn payee(recipient: &Address, share_bps: u32) -> Payee {
+    Payee {
+        recipient: recipient.clone(),
+        share_bps,
+    }
 }
 
 #[test]
-fn test_dispute_by_customer() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+fn test_release_splits_between_payees() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
+    let marketplace = Address::generate(&env);
+    let logistics = Address::generate(&env);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100_000_003,
+        DepositOptions {
+            payees: Some(vec![
+                &env,
+                payee(&merchant, 7_000),
+                payee(&marketplace, 2_000),
+                payee(&logistics, 1_000),
+            ]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(client.get_payment(&payment_id).payees.len(), 3);
+    client.release(&admin, &payment_id);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 70_000_003);
+    assert_eq!(token_client.balance(&marketplace), 20_000_000);
+    assert_eq!(token_client.balance(&logistics), 10_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_partial_release_splits_net_of_fee() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let seller = Address::generate(&env);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 100,
+            min_fee: 0,
+            max_fee: None,
+        },
+    );
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100_000_000,
+        DepositOptions {
+            payees: Some(vec![&env, payee(&seller, 8_000), payee(&merchant, 2_000)]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    client.release_partial(&admin, &payment_id, &50_000_000i128);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&seller), 39_600_000);
+    assert_eq!(token_client.balance(&merchant), 9_900_000);
+    assert_eq!(client.get_accrued_fees(&usdc), 500_000);
+
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "damaged"));
+    client.resolve_dispute(&admin, &payment_id, &merchant);
+    assert_eq!(token_client.balance(&seller), 79_200_000);
+    assert_eq!(token_client.balance(&merchant), 19_800_000);
+    assert_eq!(token_client.balance(&contract_id), 1_000_000);
+}
+
+#[test]
+fn test_dispute_refund_ignores_payees() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let marketplace = Address::generate(&env);
+
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100_000_000,
+        DepositOptions {
+            payees: Some(vec![
+                &env,
+                payee(&merchant, 9_000),
+                payee(&marketplace, 1_000),
+            ]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
     client.dispute(
         &customer,
         &payment_id,
-        &String::from_str(&env, "service not delivered"),
+        &String::from_str(&env, "never shipped"),
     );
+    client.resolve_dispute(&admin, &payment_id, &customer);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(token_client.balance(&marketplace), 0);
+}
+
+#[test]
+fn test_payee_validation() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let partner = Address::generate(&env);
+
+    for payees in [
+        vec![&env],
+        vec![&env, payee(&merchant, 9_000), payee(&partner, 999)],
+        vec![&env, payee(&merchant, 10_000), payee(&partner, 0)],
+        vec![&env, payee(&merchant, 6_000), payee(&partner, 6_000)],
+    ] {
+        assert_eq!(
+            deposit_with_options(
+                &client,
+                &customer,
+                &payment_id,
+                &merchant,
+                100,
+                DepositOptions {
+                    payees: Some(payees),
+                    ..Default::default()
+                }
+            ),
+            Err(EscrowError::InvalidPayees)
+        );
+    }
+}
+
+#[test]
+fn test_release_happy_path() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.release(&admin, &payment_id);
 
     let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Disputed);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.released_amount, 250_000_000);
+    assert_eq!(client.get_balance(&payment_id), 0);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 250_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_partial_release_multiple_steps() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.release_partial(&admin, &payment_id, &100_000_000i128);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(payment.released_amount, 100_000_000);
+    assert_eq!(client.get_balance(&payment_id), 150_000_000);
+
+    client.release_partial(&admin, &payment_id, &150_000_000i128);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.released_amount, 250_000_000);
+    assert_eq!(client.get_balance(&payment_id), 0);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 250_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_partial_release_prevents_over_release() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.release_partial(&admin, &payment_id, &200_000_000i128);
     assert_eq!(
-        payment.dispute_reason,
-        Some(String::from_str(&env, "service not delivered"))
+        client.try_release_partial(&admin, &payment_id, &100_000_000i128),
+        Err(Ok(EscrowError::ReleaseExceedsRemaining))
     );
-    assert_eq!(client.get_balance(&payment_id), 250_000_000);
 }
 
 #[test]
-fn test_dispute_by_merchant() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+fn test_refund_returns_remaining_balance_after_partial_release() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &merchant,
-        &payment_id,
-        &String::from_str(&env, "backend settlement mismatch"),
-    );
+    client.release_partial(&admin, &payment_id, &100_000_000i128);
+    env.ledger().set_sequence_number(111);
+    client.refund(&payment_id);
 
     let payment = client.get_payment(&payment_id);
-    assert_eq!(payment.status, PaymentStatus::Disputed);
+    assert_eq!(payment.status, PaymentStatus::Expired);
+    assert_eq!(payment.released_amount, 100_000_000);
+    assert_eq!(payment.refunded_amount, 150_000_000);
+    assert_eq!(client.get_balance(&payment_id), 0);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 100_000_000);
+    assert_eq!(token_client.balance(&customer), 900_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
 }
 
 #[test]
-#[should_panic(expected = "Not payment participant")]
-fn test_dispute_unauthorized_caller() {
+fn test_release_unauthorized() {
     let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
     let random = Address::generate(&env);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(&random, &payment_id, &String::from_str(&env, "not allowed"));
+    assert_eq!(
+        client.try_release(&random, &payment_id),
+        Err(Ok(EscrowError::MissingRole))
+    );
 }
 
 #[test]
-#[should_panic(expected = "Dispute already open")]
-fn test_duplicate_dispute_rejected() {
+fn test_partial_release_unauthorized() {
     let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
+    let random = Address::generate(&env);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(&customer, &payment_id, &String::from_str(&env, "first"));
-    client.dispute(&merchant, &payment_id, &String::from_str(&env, "second"));
+    assert_eq!(
+        client.try_release_partial(&random, &payment_id, &10_000_000i128),
+        Err(Ok(EscrowError::MissingRole))
+    );
 }
 
 #[test]
-#[should_panic(expected = "Dispute window expired")]
-fn test_dispute_after_window_rejected() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+fn test_release_invalid_payment_id() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+
+    assert_eq!(
+        client.try_release(&admin, &make_id(&env, 99)),
+        Err(Ok(EscrowError::PaymentNotFound))
+    );
+}
+
+#[test]
+fn test_release_expired_payment() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
     env.ledger().set_sequence_number(111);
-    client.dispute(&customer, &payment_id, &String::from_str(&env, "too late"));
+
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::PaymentExpired))
+    );
 }
 
 #[test]
-#[should_panic(expected = "Dispute is open")]
-fn test_release_blocked_while_disputed() {
+fn test_partial_release_expired_payment() {
     let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &customer,
-        &payment_id,
-        &String::from_str(&env, "hold funds"),
+    env.ledger().set_sequence_number(111);
+
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &10_000_000i128),
+        Err(Ok(EscrowError::PaymentExpired))
     );
-    client.release(&admin, &payment_id);
 }
 
 #[test]
-#[should_panic(expected = "Dispute is open")]
-fn test_partial_release_blocked_while_disputed() {
+fn test_release_already_settled_payment() {
     let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &merchant,
-        &payment_id,
-        &String::from_str(&env, "hold release"),
+    client.release(&admin, &payment_id);
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::PaymentFullyReleased))
     );
-    client.release_partial(&admin, &payment_id, &10_000_000i128);
 }
 
 #[test]
-#[should_panic(expected = "Dispute is open")]
-fn test_expire_blocked_while_disputed() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+fn test_partial_release_zero_amount() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &merchant,
-        &payment_id,
-        &String::from_str(&env, "hold refund"),
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &0i128),
+        Err(Ok(EscrowError::InvalidAmount))
     );
-    env.ledger().set_sequence_number(111);
-    client.expire(&payment_id);
 }
 
 #[test]
-fn test_resolve_dispute_to_customer() {
-    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+fn test_partial_release_fully_released_payment() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &merchant,
-        &payment_id,
-        &String::from_str(&env, "chargeback"),
+    client.release(&admin, &payment_id);
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &1i128),
+        Err(Ok(EscrowError::PaymentFullyReleased))
     );
-    client.resolve_dispute(&admin, &payment_id, &customer);
+}
+
+#[test]
+fn test_expire_refunds_customer() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    env.ledger().set_sequence_number(111);
+    client.refund(&payment_id);
 
     let payment = client.get_payment(&payment_id);
     assert_eq!(payment.status, PaymentStatus::Expired);
-    assert_eq!(payment.released_amount, 250_000_000);
+    assert_eq!(client.get_balance(&payment_id), 0);
 
     let token_client = token::Client::new(&env, &usdc);
     assert_eq!(token_client.balance(&customer), 1_000_000_000);
@@ -502,71 +1174,2911 @@ fn test_resolve_dispute_to_customer() {
 }
 
 #[test]
-fn test_resolve_dispute_to_merchant() {
-    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+fn test_expire_before_ttl() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &customer,
-        &payment_id,
-        &String::from_str(&env, "investigate"),
+    assert_eq!(
+        client.try_expire(&payment_id),
+        Err(Ok(EscrowError::PaymentNotExpired))
     );
-    client.resolve_dispute(&admin, &payment_id, &merchant);
+}
+
+#[test]
+fn test_confirm_delivery_releases_to_merchant() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 100,
+            min_fee: 0,
+            max_fee: None,
+        },
+    );
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.release_partial(&admin, &payment_id, &50_000_000i128);
+    env.ledger().set_sequence_number(20);
+    client.confirm_delivery(&payment_id);
 
     let payment = client.get_payment(&payment_id);
     assert_eq!(payment.status, PaymentStatus::Released);
     assert_eq!(payment.released_amount, 250_000_000);
+    assert_eq!(payment.dispute_window_end, 20);
+    assert_eq!(client.get_balance(&payment_id), 0);
 
     let token_client = token::Client::new(&env, &usdc);
-    assert_eq!(token_client.balance(&merchant), 250_000_000);
-    assert_eq!(token_client.balance(&contract_id), 0);
-}
-
-#[test]
-#[should_panic(expected = "Not admin")]
-fn test_resolve_dispute_unauthorized() {
-    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
-    let payment_id = make_id(&env, 1);
-    let random = Address::generate(&env);
+    assert_eq!(token_client.balance(&merchant), 247_500_000);
+    assert_eq!(client.get_accrued_fees(&usdc), 2_500_000);
+    assert_eq!(token_client.balance(&contract_id), 2_500_000);
+    assert_eq!(client.get_locked_total(&usdc), 0);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
-        &customer,
-        &payment_id,
-        &String::from_str(&env, "investigate"),
+    assert_eq!(
+        client.try_confirm_delivery(&payment_id),
+        Err(Ok(EscrowError::PaymentFullyReleased))
     );
-    client.resolve_dispute(&random, &payment_id, &merchant);
 }
 
 #[test]
-#[should_panic(expected = "Invalid dispute winner")]
-fn test_resolve_dispute_invalid_winner() {
+fn test_confirm_delivery_validation() {
     let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
-    let random = Address::generate(&env);
 
     deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
+    assert_eq!(
+        client.try_confirm_delivery(&make_id(&env, 99)),
+        Err(Ok(EscrowError::PaymentNotFound))
+    );
+
+    client.pause(&admin, &PauseScope::Releases);
+    assert_eq!(
+        client.try_confirm_delivery(&payment_id),
+        Err(Ok(EscrowError::Paused))
+    );
+    client.unpause(&admin, &PauseScope::Releases);
+
+    client.dispute(&merchant, &payment_id, &String::from_str(&env, "unpaid"));
+    assert_eq!(
+        client.try_confirm_delivery(&payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+}
+
+#[test]
+fn test_confirm_delivery_requires_customer_auth() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    env.mock_auths(&[]);
+    assert!(client.try_confirm_delivery(&payment_id).is_err());
+}
+
+#[test]
+fn test_claim_after_window_pays_merchant() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_config(
+        &admin,
+        &EscrowConfig {
+            default_ttl_ledgers: DEFAULT_PAYMENT_TTL,
+            max_ttl_ledgers: 1_000,
+            max_dispute_window_ledgers: 20,
+            dispute_bond_bps: 0,
+            forfeit_bond_to_fees: false,
+            extension_requires_merchant: false,
+        },
+    );
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
         &merchant,
+        250_000_000,
+        DepositOptions {
+            release_on_window_close: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let payment = client.get_payment(&payment_id);
+    assert!(payment.release_on_window_close);
+    assert_eq!(payment.dispute_window_end, 30);
+
+    env.ledger().set_sequence_number(30);
+    assert_eq!(
+        client.try_claim_after_window(&payment_id),
+        Err(Ok(EscrowError::DisputeWindowOpen))
+    );
+
+    env.ledger().set_sequence_number(200);
+    assert_eq!(
+        client.try_refund(&payment_id),
+        Err(Ok(EscrowError::AutoReleasePending))
+    );
+    env.mock_auths(&[]);
+    client.claim_after_window(&payment_id);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.released_amount, 250_000_000);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 250_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+    assert_eq!(
+        client.try_claim_after_window(&payment_id),
+        Err(Ok(EscrowError::PaymentFullyReleased))
+    );
+}
+
+#[test]
+fn test_claim_after_window_requires_policy() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    assert!(!client.get_payment(&payment_id).release_on_window_close);
+    env.ledger().set_sequence_number(111);
+    assert_eq!(
+        client.try_claim_after_window(&payment_id),
+        Err(Ok(EscrowError::AutoReleaseDisabled))
+    );
+    client.refund(&payment_id);
+}
+
+#[test]
+fn test_claim_after_window_blocked_by_dispute() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_with_options(
+        &client,
+        &customer,
         &payment_id,
-        &String::from_str(&env, "investigate"),
+        &merchant,
+        250_000_000,
+        DepositOptions {
+            release_on_window_close: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "broken"));
+    env.ledger().set_sequence_number(111);
+    assert_eq!(
+        client.try_claim_after_window(&payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+
+    client.resolve_dispute(&admin, &payment_id, &customer);
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(
+        client.try_claim_after_window(&payment_id),
+        Err(Ok(EscrowError::PaymentNotPending))
     );
-    client.resolve_dispute(&admin, &payment_id, &random);
+}
+
+fn milestone_spec(env: &Env, amount: i128, deadline: Option<u32>) -> MilestoneSpec {
+    MilestoneSpec {
+        amount,
+        description_hash: BytesN::from_array(env, &[amount as u8; 32]),
+        deadline,
+    }
 }
 
 #[test]
-#[should_panic(expected = "Dispute is not open")]
-fn test_resolve_dispute_already_resolved() {
+fn test_milestones_approved_in_tranches() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let random = Address::generate(&env);
+
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        250_000_000,
+        DepositOptions {
+            milestones: Some(vec![
+                &env,
+                milestone_spec(&env, 100_000_000, None),
+                milestone_spec(&env, 100_000_000, Some(50)),
+                milestone_spec(&env, 50_000_000, None),
+            ]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let milestones = client.get_milestones(&payment_id);
+    assert_eq!(milestones.len(), 3);
+    assert_eq!(milestones.get_unchecked(1).deadline, Some(50));
+    assert_eq!(milestones.get_unchecked(1).status, MilestoneStatus::Pending);
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &10_000_000i128),
+        Err(Ok(EscrowError::MilestonesActive))
+    );
+
+    client.approve_milestone(&customer, &payment_id, &0);
+    assert_eq!(
+        client.get_milestone(&payment_id, &0).status,
+        MilestoneStatus::Released
+    );
+    assert_eq!(
+        client.get_payment(&payment_id).status,
+        PaymentStatus::Pending
+    );
+    assert_eq!(client.get_balance(&payment_id), 150_000_000);
+    assert_eq!(
+        client.try_approve_milestone(&customer, &payment_id, &0),
+        Err(Ok(EscrowError::MilestoneNotPending))
+    );
+    assert_eq!(
+        client.try_approve_milestone(&random, &payment_id, &1),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    assert_eq!(
+        client.try_approve_milestone(&customer, &payment_id, &3),
+        Err(Ok(EscrowError::MilestoneNotFound))
+    );
+
+    client.approve_milestone(&admin, &payment_id, &1);
+    client.release(&admin, &payment_id);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(
+        payment.milestones.get_unchecked(2).status,
+        MilestoneStatus::Released
+    );
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 250_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+}
+
+#[test]
+fn test_milestone_deposit_validation() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            100,
+            DepositOptions {
+                milestones: Some(vec![
+                    &env,
+                    milestone_spec(&env, 100, None),
+                    milestone_spec(&env, 0, None)
+                ]),
+                ..Default::default()
+            }
+        ),
+        Err(EscrowError::InvalidMilestones)
+    );
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            100,
+            DepositOptions {
+                milestones: Some(vec![&env, milestone_spec(&env, 100, Some(10))]),
+                ..Default::default()
+            }
+        ),
+        Err(EscrowError::InvalidMilestones)
+    );
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            100,
+            DepositOptions {
+                milestones: Some(vec![&env, milestone_spec(&env, 100, None)]),
+                release_on_window_close: true,
+                ..Default::default()
+            }
+        ),
+        Err(EscrowError::InvalidMilestones)
+    );
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &payment_id,
+            &merchant,
+            &usdc,
+            &250i128,
+            &Some(DepositOptions {
+                milestones: Some(vec![&env, milestone_spec(&env, 200, None)]),
+                ..Default::default()
+            }),
+        ),
+        Err(Ok(EscrowError::InvalidMilestones))
+    );
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &payment_id,
+            &merchant,
+            &usdc,
+            &250i128,
+            &Some(DepositOptions {
+                milestones: Some(vec![&env]),
+                ..Default::default()
+            }),
+        ),
+        Err(Ok(EscrowError::InvalidMilestones))
+    );
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250i128);
+    assert_eq!(client.get_milestones(&payment_id).len(), 0);
+    assert_eq!(
+        client.try_approve_milestone(&customer, &payment_id, &0),
+        Err(Ok(EscrowError::MilestoneNotFound))
+    );
+}
+
+#[test]
+fn test_milestone_dispute_resolved_per_tranche() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        250_000_000,
+        DepositOptions {
+            milestones: Some(vec![
+                &env,
+                milestone_spec(&env, 100_000_000, None),
+                milestone_spec(&env, 150_000_000, None),
+            ]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let reason = String::from_str(&env, "first tranche incomplete");
+    client.dispute_milestone(&customer, &payment_id, &0, &reason);
+
+    let milestone = client.get_milestone(&payment_id, &0);
+    assert_eq!(milestone.status, MilestoneStatus::Disputed);
+    assert_eq!(milestone.dispute_opened_by, Some(customer.clone()));
+    assert_eq!(
+        client.try_dispute_milestone(&merchant, &payment_id, &0, &reason),
+        Err(Ok(EscrowError::DisputeAlreadyOpen))
+    );
+    assert_eq!(
+        client.try_dispute(&customer, &payment_id, &reason),
+        Err(Ok(EscrowError::DisputeAlreadyOpen))
+    );
+    assert_eq!(
+        client.try_approve_milestone(&customer, &payment_id, &0),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &250_000_000i128),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+    assert_eq!(
+        client.try_resolve_milestone_dispute(&admin, &payment_id, &1, &customer),
+        Err(Ok(EscrowError::DisputeNotOpen))
+    );
+
+    // The dispute only freezes its own tranche.
+    client.approve_milestone(&customer, &payment_id, &1);
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(payment.released_amount, 150_000_000);
+
+    client.resolve_milestone_dispute(&admin, &payment_id, &0, &customer);
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.refunded_amount, 100_000_000);
+    assert_eq!(
+        payment.milestones.get_unchecked(0).status,
+        MilestoneStatus::Refunded
+    );
+    assert_eq!(client.get_balance(&payment_id), 0);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 850_000_000);
+    assert_eq!(token_client.balance(&merchant), 150_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_milestone_dispute_window_follows_deadline() {
     let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
     let payment_id = make_id(&env, 1);
+    let reason = String::from_str(&env, "delivered on time");
+
+    client.set_config(&admin, &short_window_config(false));
+    let options = |deadline: u32| DepositOptions {
+        ttl_ledgers: Some(150),
+        milestones: Some(vec![
+            &env,
+            milestone_spec(&env, 100, Some(deadline)),
+            milestone_spec(&env, 50, Some(50)),
+            milestone_spec(&env, 150, None),
+        ]),
+        ..Default::default()
+    };
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            300,
+            options(161)
+        ),
+        Err(EscrowError::InvalidMilestones)
+    );
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        300,
+        options(100),
+    )
+    .unwrap();
+    assert_eq!(client.get_payment(&payment_id).dispute_window_end, 30);
+
+    env.ledger().set_sequence_number(40);
+    assert_eq!(
+        client.try_dispute(&merchant, &payment_id, &reason),
+        Err(Ok(EscrowError::DisputeWindowExpired))
+    );
+    client.dispute_milestone(&merchant, &payment_id, &0, &reason);
+
+    env.ledger().set_sequence_number(51);
+    assert_eq!(
+        client.try_dispute_milestone(&merchant, &payment_id, &1, &reason),
+        Err(Ok(EscrowError::DisputeWindowExpired))
+    );
+    client.refund_milestone(&payment_id, &1);
+
+    env.ledger().set_sequence_number(101);
+    assert_eq!(
+        client.try_refund_milestone(&payment_id, &0),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+    client.dispute_milestone(&customer, &payment_id, &2, &reason);
+    assert_eq!(
+        client.get_milestone(&payment_id, &2).status,
+        MilestoneStatus::Disputed
+    );
+}
+
+#[test]
+fn test_refund_milestone_after_deadline() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
 
-    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
-    client.dispute(
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        250_000_000,
+        DepositOptions {
+            milestones: Some(vec![
+                &env,
+                milestone_spec(&env, 100_000_000, Some(50)),
+                milestone_spec(&env, 150_000_000, None),
+            ]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        client.try_refund_milestone(&payment_id, &0),
+        Err(Ok(EscrowError::MilestoneDeadlineNotReached))
+    );
+
+    env.ledger().set_sequence_number(51);
+    assert_eq!(
+        client.try_refund_milestone(&payment_id, &1),
+        Err(Ok(EscrowError::MilestoneDeadlineNotReached))
+    );
+    client.refund_milestone(&payment_id, &0);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::PartiallyRefunded);
+    assert_eq!(client.get_balance(&payment_id), 150_000_000);
+    assert_eq!(
+        client.try_refund_milestone(&payment_id, &0),
+        Err(Ok(EscrowError::MilestoneNotPending))
+    );
+
+    env.ledger().set_sequence_number(111);
+    client.refund(&payment_id);
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(
+        payment.milestones.get_unchecked(1).status,
+        MilestoneStatus::Refunded
+    );
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+}
+
+#[test]
+fn test_stream_withdraw_vested() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
         &merchant,
+        100_000_000,
+        DepositOptions {
+            stream_duration_ledgers: Some(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.created_ledger, 10);
+    assert_eq!(payment.stream_end_ledger, Some(60));
+    assert_eq!(
+        client.try_withdraw_vested(&payment_id),
+        Err(Ok(EscrowError::NothingVested))
+    );
+
+    env.ledger().set_sequence_number(35);
+    assert_eq!(client.get_vested_amount(&payment_id), 50_000_000);
+    assert_eq!(client.withdraw_vested(&payment_id), 50_000_000);
+    assert_eq!(
+        client.try_withdraw_vested(&payment_id),
+        Err(Ok(EscrowError::NothingVested))
+    );
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(payment.released_amount, 50_000_000);
+
+    env.ledger().set_sequence_number(80);
+    assert_eq!(client.get_vested_amount(&payment_id), 100_000_000);
+    assert_eq!(client.withdraw_vested(&payment_id), 50_000_000);
+    assert_eq!(
+        client.get_payment(&payment_id).status,
+        PaymentStatus::Released
+    );
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 100_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+}
+
+#[test]
+fn test_cancel_stream_refunds_unvested() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_with_options(
+        &client,
+        &customer,
         &payment_id,
-        &String::from_str(&env, "investigate"),
+        &merchant,
+        100_000_000,
+        DepositOptions {
+            stream_duration_ledgers: Some(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    env.ledger().set_sequence_number(20);
+    client.withdraw_vested(&payment_id);
+    env.ledger().set_sequence_number(30);
+    client.cancel_stream(&payment_id);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.released_amount, 40_000_000);
+    assert_eq!(payment.refunded_amount, 60_000_000);
+    assert_eq!(client.get_balance(&payment_id), 0);
+    assert_eq!(
+        client.try_cancel_stream(&payment_id),
+        Err(Ok(EscrowError::PaymentFullyReleased))
+    );
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 40_000_000);
+    assert_eq!(token_client.balance(&customer), 960_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+}
+
+#[test]
+fn test_stream_validation() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            100,
+            DepositOptions {
+                stream_duration_ledgers: Some(0),
+                ..Default::default()
+            }
+        ),
+        Err(EscrowError::InvalidStream)
+    );
+    assert_eq!(
+        deposit_with_options(
+            &client,
+            &customer,
+            &payment_id,
+            &merchant,
+            100,
+            DepositOptions {
+                stream_duration_ledgers: Some(101),
+                ..Default::default()
+            }
+        ),
+        Err(EscrowError::InvalidStream)
+    );
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &payment_id,
+            &merchant,
+            &client.get_usdc_token(),
+            &100i128,
+            &Some(DepositOptions {
+                release_on_window_close: true,
+                stream_duration_ledgers: Some(50),
+                ..Default::default()
+            }),
+        ),
+        Err(Ok(EscrowError::InvalidStream))
+    );
+
+    let plain_id = make_id(&env, 2);
+    deposit_default_ttl(&client, &customer, &plain_id, &merchant, 100i128);
+    assert_eq!(
+        client.try_withdraw_vested(&plain_id),
+        Err(Ok(EscrowError::NotStream))
+    );
+    assert_eq!(
+        client.try_get_vested_amount(&plain_id),
+        Err(Ok(EscrowError::NotStream))
+    );
+
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100,
+        DepositOptions {
+            stream_duration_ledgers: Some(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &10i128),
+        Err(Ok(EscrowError::StreamActive))
+    );
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &10i128),
+        Err(Ok(EscrowError::StreamActive))
+    );
+    env.ledger().set_sequence_number(111);
+    assert_eq!(
+        client.try_refund(&payment_id),
+        Err(Ok(EscrowError::StreamActive))
+    );
+}
+
+#[test]
+fn test_stream_frozen_while_disputed() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_with_options(
+        &client,
+        &customer,
+        &payment_id,
+        &merchant,
+        100_000_000,
+        DepositOptions {
+            stream_duration_ledgers: Some(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    env.ledger().set_sequence_number(20);
+    client.withdraw_vested(&payment_id);
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "outage"));
+
+    env.ledger().set_sequence_number(40);
+    assert_eq!(
+        client.try_withdraw_vested(&payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+    assert_eq!(
+        client.try_cancel_stream(&payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+
+    client.resolve_dispute(&admin, &payment_id, &customer);
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 20_000_000);
+    assert_eq!(token_client.balance(&customer), 980_000_000);
+}
+
+#[test]
+fn test_merchant_refund_full() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.merchant_refund(&payment_id, &250_000_000i128);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Refunded);
+    assert_eq!(payment.refunded_amount, 250_000_000);
+    assert_eq!(payment.released_amount, 0);
+    assert_eq!(client.get_balance(&payment_id), 0);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::PaymentNotPending))
+    );
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &1i128),
+        Err(Ok(EscrowError::PaymentNotPending))
+    );
+}
+
+#[test]
+fn test_merchant_refund_partial_then_release() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.merchant_refund(&payment_id, &50_000_000i128);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::PartiallyRefunded);
+    assert_eq!(payment.refunded_amount, 50_000_000);
+    assert_eq!(client.get_balance(&payment_id), 200_000_000);
+
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "partial order"),
+    );
+    client.resolve_dispute(&admin, &payment_id, &merchant);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.released_amount, 200_000_000);
+    assert_eq!(client.get_balance(&payment_id), 0);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 800_000_000);
+    assert_eq!(token_client.balance(&merchant), 200_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_merchant_refund_after_partial_release() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.release_partial(&admin, &payment_id, &100_000_000i128);
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &150_000_001i128),
+        Err(Ok(EscrowError::RefundExceedsRemaining))
+    );
+    client.merchant_refund(&payment_id, &150_000_000i128);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Refunded);
+    assert_eq!(payment.released_amount, 100_000_000);
+    assert_eq!(payment.refunded_amount, 150_000_000);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 900_000_000);
+    assert_eq!(token_client.balance(&merchant), 100_000_000);
+}
+
+#[test]
+fn test_merchant_refund_validation() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &0i128),
+        Err(Ok(EscrowError::InvalidAmount))
+    );
+    assert_eq!(
+        client.try_merchant_refund(&make_id(&env, 99), &1i128),
+        Err(Ok(EscrowError::PaymentNotFound))
+    );
+
+    client.pause(&admin, &PauseScope::Refunds);
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &1i128),
+        Err(Ok(EscrowError::Paused))
+    );
+    client.unpause(&admin, &PauseScope::Refunds);
+
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "late"));
+    assert_eq!(
+        client.try_merchant_refund(&payment_id, &1i128),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+}
+
+#[test]
+fn test_merchant_refund_requires_merchant_auth() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    env.mock_auths(&[]);
+    assert!(client.try_merchant_refund(&payment_id, &1i128).is_err());
+}
+
+fn short_window_config(extension_requires_merchant: bool) -> EscrowConfig {
+    EscrowConfig {
+        default_ttl_ledgers: 50,
+        max_ttl_ledgers: 200,
+        max_dispute_window_ledgers: 20,
+        dispute_bond_bps: 0,
+        forfeit_bond_to_fees: false,
+        extension_requires_merchant,
+    }
+}
+
+#[test]
+fn test_extend_expiry_recalculates_window() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_config(&admin, &short_window_config(false));
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.expiry, 60);
+    assert_eq!(payment.dispute_window_end, 30);
+
+    env.ledger().set_sequence_number(25);
+    client.extend_expiry(&payment_id, &100);
+    assert!(env.auths().iter().all(|(address, _)| *address == customer));
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.expiry, 160);
+    assert_eq!(payment.dispute_window_end, 45);
+
+    env.ledger().set_sequence_number(161);
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::PaymentExpired))
+    );
+    client.refund(&payment_id);
+}
+
+#[test]
+fn test_extend_expiry_keeps_closed_window_closed() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let auto_release_id = make_id(&env, 2);
+
+    client.set_config(&admin, &short_window_config(false));
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    deposit_with_options(
+        &client,
+        &customer,
+        &auto_release_id,
+        &merchant,
+        100i128,
+        DepositOptions {
+            release_on_window_close: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    env.ledger().set_sequence_number(31);
+    client.extend_expiry(&payment_id, &100);
+    assert!(env.auths().iter().all(|(address, _)| *address == customer));
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.expiry, 160);
+    assert_eq!(payment.dispute_window_end, 30);
+    assert_eq!(
+        client.try_dispute(&customer, &payment_id, &String::from_str(&env, "late")),
+        Err(Ok(EscrowError::DisputeWindowExpired))
+    );
+
+    client.extend_expiry(&auto_release_id, &100);
+    let auths = env.auths();
+    assert!(auths.iter().any(|(address, _)| *address == customer));
+    assert!(auths.iter().any(|(address, _)| *address == merchant));
+    assert_eq!(client.get_payment(&auto_release_id).dispute_window_end, 30);
+    client.claim_after_window(&auto_release_id);
+}
+
+#[test]
+fn test_extend_expiry_validation() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_config(&admin, &short_window_config(false));
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    assert_eq!(
+        client.try_extend_expiry(&payment_id, &0),
+        Err(Ok(EscrowError::InvalidTtl))
+    );
+    assert_eq!(
+        client.try_extend_expiry(&payment_id, &151),
+        Err(Ok(EscrowError::TtlExceedsMaximum))
+    );
+    assert_eq!(
+        client.try_extend_expiry(&make_id(&env, 99), &10),
+        Err(Ok(EscrowError::PaymentNotFound))
+    );
+    client.pause(&admin, &PauseScope::Refunds);
+    assert_eq!(
+        client.try_extend_expiry(&payment_id, &10),
+        Err(Ok(EscrowError::Paused))
+    );
+    client.unpause(&admin, &PauseScope::Refunds);
+
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "late"));
+    assert_eq!(
+        client.try_extend_expiry(&payment_id, &10),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+
+    let expired_id = make_id(&env, 2);
+    deposit_default_ttl(&client, &customer, &expired_id, &merchant, 250_000_000i128);
+    env.ledger().set_sequence_number(61);
+    assert_eq!(
+        client.try_extend_expiry(&expired_id, &10),
+        Err(Ok(EscrowError::PaymentExpired))
+    );
+}
+
+#[test]
+fn test_extend_expiry_can_require_merchant() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_config(&admin, &short_window_config(true));
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.extend_expiry(&payment_id, &50);
+
+    let auths = env.auths();
+    assert!(auths.iter().any(|(address, _)| *address == customer));
+    assert!(auths.iter().any(|(address, _)| *address == merchant));
+    assert_eq!(client.get_payment(&payment_id).expiry, 110);
+}
+
+#[test]
+fn test_dispute_by_customer() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "service not delivered"),
+    );
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Disputed);
+    assert_eq!(
+        payment.dispute_reason,
+        Some(String::from_str(&env, "service not delivered"))
+    );
+    assert_eq!(client.get_balance(&payment_id), 250_000_000);
+}
+
+#[test]
+fn test_dispute_by_merchant() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "backend settlement mismatch"),
+    );
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Disputed);
+}
+
+#[test]
+fn test_dispute_unauthorized_caller() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let random = Address::generate(&env);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    assert_eq!(
+        client.try_dispute(&random, &payment_id, &String::from_str(&env, "not allowed")),
+        Err(Ok(EscrowError::NotParticipant))
+    );
+}
+
+#[test]
+fn test_duplicate_dispute_rejected() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "first"));
+    assert_eq!(
+        client.try_dispute(&merchant, &payment_id, &String::from_str(&env, "second")),
+        Err(Ok(EscrowError::DisputeAlreadyOpen))
+    );
+}
+
+#[test]
+fn test_dispute_after_window_rejected() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    env.ledger().set_sequence_number(111);
+    assert_eq!(
+        client.try_dispute(&customer, &payment_id, &String::from_str(&env, "too late")),
+        Err(Ok(EscrowError::DisputeWindowExpired))
+    );
+}
+
+#[test]
+fn test_release_blocked_while_disputed() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "hold funds"),
+    );
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+}
+
+#[test]
+fn test_partial_release_blocked_while_disputed() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "hold release"),
+    );
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &10_000_000i128),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+}
+
+#[test]
+fn test_expire_blocked_while_disputed() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "hold refund"),
+    );
+    env.ledger().set_sequence_number(111);
+    assert_eq!(
+        client.try_expire(&payment_id),
+        Err(Ok(EscrowError::DisputeOpen))
+    );
+}
+
+#[test]
+fn test_resolve_dispute_to_customer() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "chargeback"),
+    );
+    client.resolve_dispute(&admin, &payment_id, &customer);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Expired);
+    assert_eq!(payment.released_amount, 0);
+    assert_eq!(payment.refunded_amount, 250_000_000);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_resolve_dispute_to_merchant() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "investigate"),
+    );
+    client.resolve_dispute(&admin, &payment_id, &merchant);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.status, PaymentStatus::Released);
+    assert_eq!(payment.released_amount, 250_000_000);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 250_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_resolve_dispute_unauthorized() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let random = Address::generate(&env);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "investigate"),
+    );
+    assert_eq!(
+        client.try_resolve_dispute(&random, &payment_id, &merchant),
+        Err(Ok(EscrowError::MissingRole))
+    );
+}
+
+#[test]
+fn test_resolve_dispute_invalid_winner() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let random = Address::generate(&env);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "investigate"),
+    );
+    assert_eq!(
+        client.try_resolve_dispute(&admin, &payment_id, &random),
+        Err(Ok(EscrowError::InvalidDisputeWinner))
+    );
+}
+
+#[test]
+fn test_resolve_dispute_already_resolved() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "investigate"),
+    );
+    client.resolve_dispute(&admin, &payment_id, &merchant);
+    assert_eq!(
+        client.try_resolve_dispute(&admin, &payment_id, &merchant),
+        Err(Ok(EscrowError::DisputeNotOpen))
+    );
+}
+
+#[test]
+fn test_admin_rotation() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let new_admin = Address::generate(&env);
+
+    client.propose_admin(&admin, &new_admin);
+    assert_eq!(client.get_pending_admin(), Some(new_admin.clone()));
+    assert_eq!(client.get_admin(), admin);
+
+    client.accept_admin(&new_admin);
+    assert_eq!(client.get_admin(), new_admin);
+    assert_eq!(client.get_pending_admin(), None);
+    for role in [
+        Role::Releaser,
+        Role::Arbiter,
+        Role::Pauser,
+        Role::FeeManager,
+        Role::RoleAdmin,
+        Role::FeeCollector,
+    ] {
+        assert!(!client.has_role(&role, &admin));
+        assert!(client.has_role(&role, &new_admin));
+    }
+
+    assert_eq!(
+        client.try_propose_admin(&admin, &admin),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+    assert_eq!(
+        client.try_grant_role(&admin, &Role::Releaser, &admin),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    assert_eq!(
+        client.try_pause(&admin, &PauseScope::All),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    client.propose_admin(&new_admin, &admin);
+}
+
+#[test]
+fn test_admin_rotation_keeps_roles_revoked_from_admin() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let new_admin = Address::generate(&env);
+
+    client.revoke_role(&admin, &Role::Pauser, &admin);
+    client.propose_admin(&admin, &new_admin);
+    client.accept_admin(&new_admin);
+
+    assert!(!client.has_role(&Role::Pauser, &new_admin));
+    assert!(client.has_role(&Role::Releaser, &new_admin));
+}
+
+#[test]
+fn test_propose_admin_unauthorized() {
+    let (env, client, _contract_id, _admin, _customer, _merchant, _usdc) = setup_env();
+    let random = Address::generate(&env);
+
+    assert_eq!(
+        client.try_propose_admin(&random, &random),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+}
+
+#[test]
+fn test_accept_admin_wrong_address() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let new_admin = Address::generate(&env);
+    let random = Address::generate(&env);
+
+    assert_eq!(
+        client.try_accept_admin(&new_admin),
+        Err(Ok(EscrowError::NoPendingAdmin))
+    );
+
+    client.propose_admin(&admin, &new_admin);
+    assert_eq!(
+        client.try_accept_admin(&random),
+        Err(Ok(EscrowError::NotPendingAdmin))
+    );
+}
+
+#[test]
+fn test_cancel_admin_proposal() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let new_admin = Address::generate(&env);
+
+    client.propose_admin(&admin, &new_admin);
+    client.cancel_admin_proposal(&admin);
+
+    assert_eq!(client.get_pending_admin(), None);
+    assert_eq!(
+        client.try_accept_admin(&new_admin),
+        Err(Ok(EscrowError::NoPendingAdmin))
+    );
+    assert_eq!(
+        client.try_cancel_admin_proposal(&admin),
+        Err(Ok(EscrowError::NoPendingAdmin))
+    );
+}
+
+#[test]
+fn test_constructor_grants_roles_to_admin() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let random = Address::generate(&env);
+
+    for role in [
+        Role::Releaser,
+        Role::Arbiter,
+        Role::Pauser,
+        Role::FeeManager,
+        Role::RoleAdmin,
+        Role::FeeCollector,
+    ] {
+        assert!(client.has_role(&role, &admin));
+        assert!(!client.has_role(&role, &random));
+    }
+}
+
+#[test]
+fn test_separate_releaser_and_arbiter() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let releaser = Address::generate(&env);
+    let arbiter = Address::generate(&env);
+    let first = make_id(&env, 1);
+    let second = make_id(&env, 2);
+
+    client.grant_role(&admin, &Role::Releaser, &releaser);
+    client.grant_role(&admin, &Role::Arbiter, &arbiter);
+
+    deposit_default_ttl(&client, &customer, &first, &merchant, 250_000_000i128);
+    deposit_default_ttl(&client, &customer, &second, &merchant, 250_000_000i128);
+    client.dispute(&customer, &second, &String::from_str(&env, "investigate"));
+
+    assert_eq!(
+        client.try_release(&arbiter, &first),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    assert_eq!(
+        client.try_resolve_dispute(&releaser, &second, &customer),
+        Err(Ok(EscrowError::MissingRole))
+    );
+
+    client.release(&releaser, &first);
+    client.resolve_dispute(&arbiter, &second, &customer);
+
+    assert_eq!(client.get_payment(&first).status, PaymentStatus::Released);
+    assert_eq!(client.get_payment(&second).status, PaymentStatus::Expired);
+}
+
+#[test]
+fn test_multiple_role_holders_and_revoke() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let worker_a = Address::generate(&env);
+    let worker_b = Address::generate(&env);
+    let payment_id = make_id(&env, 1);
+
+    client.grant_role(&admin, &Role::Releaser, &worker_a);
+    client.grant_role(&admin, &Role::Releaser, &worker_b);
+    assert!(client.has_role(&Role::Releaser, &worker_a));
+    assert!(client.has_role(&Role::Releaser, &worker_b));
+
+    client.revoke_role(&admin, &Role::Releaser, &worker_a);
+    assert!(!client.has_role(&Role::Releaser, &worker_a));
+    assert!(client.has_role(&Role::Releaser, &worker_b));
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    assert_eq!(
+        client.try_release_partial(&worker_a, &payment_id, &1i128),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    client.release_partial(&worker_b, &payment_id, &1i128);
+}
+
+#[test]
+fn test_role_admin_delegation() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let role_admin = Address::generate(&env);
+    let pauser = Address::generate(&env);
+    let random = Address::generate(&env);
+
+    assert_eq!(
+        client.try_grant_role(&random, &Role::Pauser, &pauser),
+        Err(Ok(EscrowError::MissingRole))
+    );
+
+    client.grant_role(&admin, &Role::RoleAdmin, &role_admin);
+    client.grant_role(&role_admin, &Role::Pauser, &pauser);
+    assert!(client.has_role(&Role::Pauser, &pauser));
+
+    client.revoke_role(&admin, &Role::RoleAdmin, &admin);
+    client.revoke_role(&admin, &Role::RoleAdmin, &role_admin);
+    assert_eq!(
+        client.try_revoke_role(&role_admin, &Role::Pauser, &pauser),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    client.revoke_role(&admin, &Role::Pauser, &pauser);
+    assert!(!client.has_role(&Role::Pauser, &pauser));
+}
+
+#[test]
+fn test_pause_all_blocks_every_scope() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.pause(&admin, &PauseScope::All);
+
+    assert_eq!(
+        client.get_pause_state(),
+        PauseState {
+            deposits: true,
+            releases: true,
+            refunds: true,
+            disputes: true,
+        }
+    );
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 2),
+            &merchant,
+            &usdc,
+            &250_000_000i128,
+            &ttl_options(DEFAULT_PAYMENT_TTL)
+        ),
+        Err(Ok(EscrowError::Paused))
+    );
+    assert_eq!(
+        client.try_release(&admin, &payment_id),
+        Err(Ok(EscrowError::Paused))
+    );
+    assert_eq!(
+        client.try_release_partial(&admin, &payment_id, &1i128),
+        Err(Ok(EscrowError::Paused))
+    );
+    assert_eq!(
+        client.try_dispute(&customer, &payment_id, &String::from_str(&env, "paused")),
+        Err(Ok(EscrowError::Paused))
+    );
+    env.ledger().set_sequence_number(111);
+    assert_eq!(client.try_refund(&payment_id), Err(Ok(EscrowError::Paused)));
+
+    client.unpause(&admin, &PauseScope::All);
+    assert_eq!(client.get_pause_state(), PauseState::default());
+    client.refund(&payment_id);
+}
+
+#[test]
+fn test_pause_single_scope() {
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "hold"));
+    client.pause(&admin, &PauseScope::Disputes);
+
+    assert_eq!(
+        client.try_resolve_dispute(&admin, &payment_id, &customer),
+        Err(Ok(EscrowError::Paused))
+    );
+    deposit_default_ttl(&client, &customer, &make_id(&env, 2), &merchant, 1_000i128);
+    client.release(&admin, &make_id(&env, 2));
+
+    client.unpause(&admin, &PauseScope::Disputes);
+    client.resolve_dispute(&admin, &payment_id, &customer);
+}
+
+#[test]
+fn test_pause_requires_pauser_role() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let pauser = Address::generate(&env);
+
+    assert_eq!(
+        client.try_pause(&pauser, &PauseScope::Deposits),
+        Err(Ok(EscrowError::MissingRole))
+    );
+
+    client.grant_role(&admin, &Role::Pauser, &pauser);
+    client.pause(&pauser, &PauseScope::Deposits);
+    assert!(client.get_pause_state().deposits);
+    assert!(!client.get_pause_state().releases);
+
+    client.revoke_role(&admin, &Role::Pauser, &pauser);
+    assert_eq!(
+        client.try_unpause(&pauser, &PauseScope::Deposits),
+        Err(Ok(EscrowError::MissingRole))
+    );
+}
+
+mod v1 {
+    use crate::{PaymentEscrowV1, PaymentStatus};
+    use soroban_sdk::{contract, contractimpl, contracttype, token, Address, BytesN, Env, String};
+
+    #[contracttype(export = false)]
+    #[derive(Clone)]
+    enum DataKey {
+        Admin,
+        UsdcToken,
+        DefaultTtlLedgers,
+        Payment(BytesN<32>),
+    }
+
+    #[contract]
+    pub struct PaymentEscrowV1Contract;
+
+    #[contractimpl]
+    impl PaymentEscrowV1Contract {
+        pub fn __constructor(
+            env: Env,
+            admin: Address,
+            usdc_token: Address,
+            default_ttl_ledgers: u32,
+        ) {
+            env.storage().instance().set(&DataKey::Admin, &admin);
+            env.storage()
+                .instance()
+                .set(&DataKey::UsdcToken, &usdc_token);
+            env.storage()
+                .instance()
+                .set(&DataKey::DefaultTtlLedgers, &default_ttl_ledgers);
+        }
+
+        pub fn deposit(
+            env: Env,
+            customer: Address,
+            payment_id: BytesN<32>,
+            merchant: Address,
+            amount: i128,
+            ttl_ledgers: u32,
+        ) {
+            customer.require_auth();
+
+            let usdc_token: Address = env.storage().instance().get(&DataKey::UsdcToken).unwrap();
+            token::Client::new(&env, &usdc_token).transfer(
+                &customer,
+                env.current_contract_address(),
+                &amount,
+            );
+
+            let expiry = env.ledger().sequence() + ttl_ledgers;
+            let payment = PaymentEscrowV1 {
+                payment_id: payment_id.clone(),
+                amount,
+                released_amount: 0,
+                merchant,
+                customer,
+                status: PaymentStatus::Pending,
+                expiry,
+                dispute_window_end: expiry,
+                dispute_reason: None,
+            };
+            env.storage()
+                .persistent()
+                .set(&DataKey::Payment(payment_id), &payment);
+        }
+
+        pub fn dispute(env: Env, payment_id: BytesN<32>, reason: String) {
+            let key = DataKey::Payment(payment_id);
+            let mut payment: PaymentEscrowV1 = env.storage().persistent().get(&key).unwrap();
+            payment.status = PaymentStatus::Disputed;
+            payment.dispute_reason = Some(reason);
+            env.storage().persistent().set(&key, &payment);
+        }
+    }
+}
+
+fn setup_v1_env() -> (
+    Env,
+    v1::PaymentEscrowV1ContractClient<'static>,
+    Address,
+    Address,
+    Address,
+    Address,
+    Address,
+) {
+    let env = Env::default();
+    env.mock_all_auths();
+    env.ledger().set_sequence_number(10);
+
+    let admin = Address::generate(&env);
+    let customer = Address::generate(&env);
+    let merchant = Address::generate(&env);
+    let token_admin = Address::generate(&env);
+    let asset_contract = env.register_stellar_asset_contract_v2(token_admin);
+    let usdc = asset_contract.address();
+
+    let contract_id = env.register(
+        v1::PaymentEscrowV1Contract,
+        (&admin, &usdc, &DEFAULT_PAYMENT_TTL),
+    );
+    let client = v1::PaymentEscrowV1ContractClient::new(&env, &contract_id);
+
+    let token_admin_client = token::StellarAssetClient::new(&env, &usdc);
+    token_admin_client.mint(&customer, &1_000_000_000i128);
+
+    (env, client, contract_id, admin, customer, merchant, usdc)
+}
+
+fn upgrade_from_v1(
+    env: &Env,
+    contract_id: &Address,
+    admin: &Address,
+    usdc: &Address,
+) -> PaymentEscrowContractClient<'static> {
+    env.register_at(
+        contract_id,
+        PaymentEscrowContract,
+        (admin, usdc, &DEFAULT_PAYMENT_TTL),
+    );
+    // Re-registration runs the constructor, which an on-chain Wasm update does
+    // not, so drop what it wrote to leave the v1 instance state behind.
+    env.as_contract(contract_id, || {
+        env.storage().instance().remove(&DataKey::SchemaVersion);
+        env.storage().instance().remove(&DataKey::Config);
+        env.storage().instance().remove(&DataKey::AllowedTokens);
+        for role in [
+            Role::Releaser,
+            Role::Arbiter,
+            Role::Pauser,
+            Role::FeeManager,
+            Role::RoleAdmin,
+            Role::FeeCollector,
+        ] {
+            env.storage()
+                .persistent()
+                .remove(&DataKey::Role(role, admin.clone()));
+        }
+    });
+    PaymentEscrowContractClient::new(env, contract_id)
+}
+
+#[test]
+fn test_version_is_current_schema() {
+    let (_env, client, _contract_id, _admin, _customer, _merchant, _usdc) = setup_env();
+
+    assert_eq!(client.version(), 2);
+}
+
+#[test]
+fn test_upgrade_unauthorized() {
+    let (env, client, _contract_id, _admin, _customer, _merchant, _usdc) = setup_env();
+    let random = Address::generate(&env);
+
+    assert_eq!(
+        client.try_upgrade(&random, &BytesN::from_array(&env, &[0; 32])),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+    assert_eq!(
+        client.try_migrate(&random, &vec![&env]),
+        Err(Ok(EscrowError::NotAdmin))
+    );
+}
+
+#[test]
+#[ignore = "needs the Wasm from `stellar contract build`"]
+fn test_upgrade_with_uploaded_wasm() {
+    extern crate std;
+
+    let wasm = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../target/wasm32v1-none/release/payment_escrow.wasm"
+    ))
+    .expect("build the contract with `stellar contract build` first");
+    let (env, client, _contract_id, admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+
+    let wasm_hash = env
+        .deployer()
+        .upload_contract_wasm(Bytes::from_slice(&env, &wasm));
+    client.upgrade(&admin, &wasm_hash);
+
+    assert_eq!(client.version(), 2);
+    assert_eq!(client.get_admin(), admin);
+    client.release(&admin, &payment_id);
+    assert_eq!(
+        client.get_payment(&payment_id).status,
+        PaymentStatus::Released
+    );
+}
+
+#[test]
+fn test_upgrade_from_v1_keeps_in_flight_payments() {
+    let (env, v1_client, contract_id, admin, customer, merchant, usdc) = setup_v1_env();
+    let pending = make_id(&env, 1);
+    let disputed = make_id(&env, 2);
+    let expiring = make_id(&env, 3);
+
+    v1_client.deposit(&customer, &pending, &merchant, &100_000_000i128, &50u32);
+    v1_client.deposit(&customer, &disputed, &merchant, &200_000_000i128, &50u32);
+    v1_client.deposit(&customer, &expiring, &merchant, &300_000_000i128, &20u32);
+    v1_client.dispute(&disputed, &String::from_str(&env, "not delivered"));
+
+    // The v1 contract is registered natively, so the new code is swapped in
+    // with `register_at` instead of calling `upgrade` with a Wasm hash; the
+    // entry point itself is exercised by `test_upgrade_with_uploaded_wasm`.
+    let client = upgrade_from_v1(&env, &contract_id, &admin, &usdc);
+    assert_eq!(client.version(), 1);
+    assert_eq!(client.get_default_ttl_ledgers(), DEFAULT_PAYMENT_TTL);
+    assert_eq!(client.get_payment(&pending).amount, 100_000_000);
+    assert_eq!(
+        client.try_deposit(&customer, &pending, &merchant, &usdc, &1i128, &None),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
+
+    client.migrate(&admin, &vec![&env, pending.clone(), disputed.clone()]);
+    assert_eq!(client.version(), 2);
+    assert_eq!(client.get_locked_total(&usdc), 300_000_000);
+    assert!(client.has_role(&Role::Releaser, &admin));
+
+    let migrated = client.get_payment(&disputed);
+    assert_eq!(migrated.status, PaymentStatus::Disputed);
+    assert_eq!(
+        migrated.dispute_reason,
+        Some(String::from_str(&env, "not delivered"))
+    );
+    assert_eq!(migrated.dispute_opened_by, None);
+    assert_eq!(migrated.token, usdc);
+
+    client.release(&admin, &pending);
+    client.resolve_dispute(&admin, &disputed, &customer);
+    env.ledger().set_sequence_number(31);
+    client.refund(&expiring);
+
+    assert_eq!(client.get_payment(&pending).status, PaymentStatus::Released);
+    assert_eq!(client.get_payment(&disputed).status, PaymentStatus::Expired);
+    assert_eq!(client.get_payment(&expiring).status, PaymentStatus::Expired);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 100_000_000);
+    assert_eq!(token_client.balance(&customer), 900_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+}
+
+#[test]
+fn test_migrate_rewrites_legacy_records() {
+    let (env, v1_client, contract_id, admin, customer, merchant, usdc) = setup_v1_env();
+    let payment_id = make_id(&env, 1);
+
+    v1_client.deposit(&customer, &payment_id, &merchant, &100_000_000i128, &50u32);
+    let client = upgrade_from_v1(&env, &contract_id, &admin, &usdc);
+    client.migrate(&admin, &vec![&env, payment_id.clone()]);
+
+    env.as_contract(&contract_id, || {
+        assert!(!env
+            .storage()
+            .persistent()
+            .has(&DataKey::Payment(payment_id.clone())));
+        assert!(env
+            .storage()
+            .persistent()
+            .has(&DataKey::PaymentV2(payment_id.clone())));
+    });
+
+    client.migrate(&admin, &vec![&env, payment_id.clone()]);
+    assert_eq!(client.get_payment(&payment_id).amount, 100_000_000);
+}
+
+#[test]
+fn test_release_deducts_fee() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 150,
+            min_fee: 0,
+            max_fee: None,
+        },
+    );
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 200_000_000i128);
+    client.release(&admin, &payment_id);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 197_000_000);
+    assert_eq!(token_client.balance(&contract_id), 3_000_000);
+    assert_eq!(client.get_accrued_fees(&usdc), 3_000_000);
+    assert_eq!(client.get_locked_total(&usdc), 0);
+}
+
+#[test]
+fn test_partial_release_fee_clamped() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 100,
+            min_fee: 500_000,
+            max_fee: Some(1_000_000),
+        },
+    );
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 250_000_000i128);
+    client.release_partial(&admin, &payment_id, &10_000_000i128);
+    client.release_partial(&admin, &payment_id, &240_000_000i128);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(client.get_accrued_fees(&usdc), 1_500_000);
+    assert_eq!(token_client.balance(&merchant), 248_500_000);
+    assert_eq!(client.get_payment(&payment_id).released_amount, 250_000_000);
+}
+
+#[test]
+fn test_dispute_refund_to_customer_is_fee_free() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let refunded = make_id(&env, 1);
+    let awarded = make_id(&env, 2);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 1_000,
+            min_fee: 0,
+            max_fee: None,
+        },
+    );
+    deposit_default_ttl(&client, &customer, &refunded, &merchant, 100_000_000i128);
+    deposit_default_ttl(&client, &customer, &awarded, &merchant, 100_000_000i128);
+    client.dispute(&customer, &refunded, &String::from_str(&env, "refund"));
+    client.dispute(&customer, &awarded, &String::from_str(&env, "award"));
+    client.resolve_dispute(&admin, &refunded, &customer);
+    client.resolve_dispute(&admin, &awarded, &merchant);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 900_000_000);
+    assert_eq!(token_client.balance(&merchant), 90_000_000);
+    assert_eq!(client.get_accrued_fees(&usdc), 10_000_000);
+}
+
+#[test]
+fn test_withdraw_fees() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let collector = Address::generate(&env);
+    let treasury = Address::generate(&env);
+    let payment_id = make_id(&env, 1);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 200,
+            min_fee: 0,
+            max_fee: None,
+        },
+    );
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 100_000_000i128);
+    client.release(&admin, &payment_id);
+
+    assert_eq!(
+        client.try_withdraw_fees(&collector, &usdc, &treasury, &1i128),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    client.grant_role(&admin, &Role::FeeCollector, &collector);
+    assert_eq!(
+        client.try_withdraw_fees(&collector, &usdc, &treasury, &2_000_001i128),
+        Err(Ok(EscrowError::InsufficientFees))
+    );
+    client.pause(&admin, &PauseScope::Releases);
+    assert_eq!(
+        client.try_withdraw_fees(&collector, &usdc, &treasury, &1_500_000i128),
+        Err(Ok(EscrowError::Paused))
+    );
+    client.unpause(&admin, &PauseScope::Releases);
+
+    client.withdraw_fees(&collector, &usdc, &treasury, &1_500_000i128);
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&treasury), 1_500_000);
+    assert_eq!(token_client.balance(&contract_id), 500_000);
+    assert_eq!(client.get_accrued_fees(&usdc), 500_000);
+}
+
+#[test]
+fn test_set_fee_schedule_validation() {
+    let (env, client, _contract_id, admin, _customer, _merchant, _usdc) = setup_env();
+    let random = Address::generate(&env);
+
+    assert_eq!(client.get_fee_schedule(), FeeSchedule::default());
+    assert_eq!(
+        client.try_set_fee_schedule(&random, &FeeSchedule::default()),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    for invalid in [
+        FeeSchedule {
+            fee_bps: 10_001,
+            min_fee: 0,
+            max_fee: None,
+        },
+        FeeSchedule {
+            fee_bps: 100,
+            min_fee: -1,
+            max_fee: None,
+        },
+        FeeSchedule {
+            fee_bps: 100,
+            min_fee: 10,
+            max_fee: Some(9),
+        },
+    ] {
+        assert_eq!(
+            client.try_set_fee_schedule(&admin, &invalid),
+            Err(Ok(EscrowError::InvalidConfig))
+        );
+    }
+}
+
+#[test]
+fn test_merchant_fee_tier_scales_rate() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let other_merchant = Address::generate(&env);
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 200,
+            min_fee: 100_000,
+            max_fee: None,
+        },
+    );
+    client.set_fee_tier(&admin, &1u32, &5_000u32);
+    client.set_merchant_fee_tier(&admin, &merchant, &1u32);
+
+    assert_eq!(client.get_merchant_fee_tier(&merchant), Some(1));
+    assert_eq!(client.preview_fee(&merchant, &100_000_000i128), 1_000_000);
+    assert_eq!(
+        client.preview_fee(&other_merchant, &100_000_000i128),
+        2_000_000
+    );
+    assert_eq!(client.preview_fee(&merchant, &1_000_000i128), 100_000);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 100_000_000i128);
+    client.release(&admin, &payment_id);
+    assert_eq!(client.get_accrued_fees(&usdc), 1_000_000);
+    assert_eq!(
+        token::Client::new(&env, &usdc).balance(&merchant),
+        99_000_000
+    );
+
+    client.clear_fee_tier(&admin, &1u32);
+    assert_eq!(client.get_fee_tier(&1u32), None);
+    assert_eq!(client.preview_fee(&merchant, &100_000_000i128), 2_000_000);
+    assert_eq!(
+        client.try_set_merchant_fee_tier(&admin, &other_merchant, &1u32),
+        Err(Ok(EscrowError::FeeTierNotFound))
+    );
+
+    client.set_fee_tier(&admin, &1u32, &5_000u32);
+    client.clear_merchant_fee_tier(&admin, &merchant);
+    assert_eq!(client.get_merchant_fee_tier(&merchant), None);
+    assert_eq!(client.preview_fee(&merchant, &100_000_000i128), 2_000_000);
+}
+
+#[test]
+fn test_merchant_fee_override() {
+    let (_env, client, _contract_id, admin, _customer, merchant, _usdc) = setup_env();
+    let schedule = FeeSchedule {
+        fee_bps: 50,
+        min_fee: 0,
+        max_fee: Some(300_000),
+    };
+
+    client.set_fee_schedule(
+        &admin,
+        &FeeSchedule {
+            fee_bps: 200,
+            min_fee: 0,
+            max_fee: None,
+        },
+    );
+    client.set_fee_tier(&admin, &1u32, &20_000u32);
+    client.set_merchant_fee_tier(&admin, &merchant, &1u32);
+    assert_eq!(client.preview_fee(&merchant, &10_000_000i128), 400_000);
+
+    client.set_merchant_fee_override(&admin, &merchant, &schedule);
+    assert_eq!(client.get_merchant_fee_override(&merchant), Some(schedule));
+    assert_eq!(client.preview_fee(&merchant, &10_000_000i128), 50_000);
+    assert_eq!(client.preview_fee(&merchant, &100_000_000i128), 300_000);
+
+    client.clear_merchant_fee_override(&admin, &merchant);
+    assert_eq!(client.get_merchant_fee_override(&merchant), None);
+    assert_eq!(client.preview_fee(&merchant, &10_000_000i128), 400_000);
+}
+
+#[test]
+fn test_merchant_fee_tier_validation() {
+    let (env, client, _contract_id, admin, _customer, merchant, _usdc) = setup_env();
+    let random = Address::generate(&env);
+
+    assert_eq!(
+        client.try_set_merchant_fee_tier(&admin, &merchant, &7u32),
+        Err(Ok(EscrowError::FeeTierNotFound))
+    );
+    assert_eq!(
+        client.try_set_fee_tier(&random, &7u32, &5_000u32),
+        Err(Ok(EscrowError::MissingRole))
+    );
+    assert_eq!(
+        client.try_set_fee_tier(&admin, &7u32, &100_001u32),
+        Err(Ok(EscrowError::InvalidConfig))
+    );
+    assert_eq!(
+        client.try_clear_fee_tier(&admin, &7u32),
+        Err(Ok(EscrowError::FeeTierNotFound))
+    );
+    assert_eq!(
+        client.try_set_merchant_fee_override(
+            &admin,
+            &merchant,
+            &FeeSchedule {
+                fee_bps: 10_001,
+                min_fee: 0,
+                max_fee: None,
+            }
+        ),
+        Err(Ok(EscrowError::InvalidConfig))
+    );
+    assert_eq!(client.get_fee_tier(&7u32), None);
+}
+
+fn enable_dispute_bond(client: &PaymentEscrowContractClient<'_>, admin: &Address, bps: u32) {
+    client.set_config(
+        admin,
+        &EscrowConfig {
+            dispute_bond_bps: bps,
+            ..client.get_config()
+        },
+    );
+}
+
+#[test]
+fn test_dispute_bond_returned_to_winning_opener() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    enable_dispute_bond(&client, &admin, 500);
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 200_000_000i128);
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "not delivered"),
+    );
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(client.get_payment(&payment_id).dispute_bond, 10_000_000);
+    assert_eq!(token_client.balance(&customer), 790_000_000);
+    assert_eq!(token_client.balance(&contract_id), 210_000_000);
+    assert_eq!(client.get_locked_total(&usdc), 200_000_000);
+
+    client.resolve_dispute(&admin, &payment_id, &customer);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_dispute_bond_forfeited_to_counterparty() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    enable_dispute_bond(&client, &admin, 500);
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 200_000_000i128);
+    client.dispute(&customer, &payment_id, &String::from_str(&env, "frivolous"));
+    client.resolve_dispute(&admin, &payment_id, &merchant);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 210_000_000);
+    assert_eq!(token_client.balance(&customer), 790_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_dispute_bond_forfeited_to_fee_pool() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.set_config(
+        &admin,
+        &EscrowConfig {
+            dispute_bond_bps: 1_000,
+            forfeit_bond_to_fees: true,
+            extension_requires_merchant: false,
+            ..client.get_config()
+        },
+    );
+    token::StellarAssetClient::new(&env, &usdc).mint(&merchant, &50_000_000i128);
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 200_000_000i128);
+    client.release_partial(&admin, &payment_id, &100_000_000i128);
+    client.dispute(
+        &merchant,
+        &payment_id,
+        &String::from_str(&env, "chargeback"),
+    );
+    assert_eq!(client.get_payment(&payment_id).dispute_bond, 10_000_000);
+
+    client.resolve_dispute(&admin, &payment_id, &customer);
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 140_000_000);
+    assert_eq!(token_client.balance(&customer), 900_000_000);
+    assert_eq!(client.get_accrued_fees(&usdc), 10_000_000);
+    assert_eq!(token_client.balance(&contract_id), 10_000_000);
+}
+
+fn sponsor_with_funds(env: &Env, usdc: &Address) -> Address {
+    let sponsor = Address::generate(env);
+    token::StellarAssetClient::new(env, usdc).mint(&sponsor, &1_000_000_000i128);
+    sponsor
+}
+
+#[test]
+fn test_deposit_for_charges_payer() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let sponsor = sponsor_with_funds(&env, &usdc);
+
+    client.deposit_for(
+        &sponsor,
+        &customer,
+        &payment_id,
+        &merchant,
+        &usdc,
+        &100_000_000i128,
+        &None,
+    );
+    assert_eq!(env.auths().len(), 1);
+    assert_eq!(env.auths()[0].0, sponsor);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.payer, sponsor);
+    assert_eq!(payment.customer, customer);
+    assert_eq!(payment.refund_to, None);
+
+    client.top_up(&payment_id, &50_000_000i128);
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&sponsor), 850_000_000);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(token_client.balance(&contract_id), 150_000_000);
+
+    client.dispute(
+        &customer,
+        &payment_id,
+        &String::from_str(&env, "gift never arrived"),
+    );
+    client.resolve_dispute(&admin, &payment_id, &customer);
+    assert_eq!(token_client.balance(&customer), 1_150_000_000);
+    assert_eq!(token_client.balance(&sponsor), 850_000_000);
+}
+
+#[test]
+fn test_deposit_for_refunds_to_refund_address() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let sponsor = sponsor_with_funds(&env, &usdc);
+    let options = Some(DepositOptions {
+        refund_to: Some(sponsor.clone()),
+        ..Default::default()
+    });
+    let token_client = token::Client::new(&env, &usdc);
+
+    let disputed_id = make_id(&env, 1);
+    client.deposit_for(
+        &sponsor,
+        &customer,
+        &disputed_id,
+        &merchant,
+        &usdc,
+        &100_000_000i128,
+        &options,
+    );
+    enable_dispute_bond(&client, &admin, 1_000);
+    client.dispute(
+        &customer,
+        &disputed_id,
+        &String::from_str(&env, "expense rejected"),
+    );
+    assert_eq!(token_client.balance(&customer), 990_000_000);
+    client.resolve_dispute(&admin, &disputed_id, &customer);
+    assert_eq!(token_client.balance(&sponsor), 1_000_000_000);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+
+    let expiring_id = make_id(&env, 2);
+    client.deposit_for(
+        &sponsor,
+        &customer,
+        &expiring_id,
+        &merchant,
+        &usdc,
+        &100_000_000i128,
+        &options,
+    );
+    client.merchant_refund(&expiring_id, &40_000_000i128);
+    assert_eq!(
+        env.events().all().filter_by_contract(&contract_id),
+        [RefundEvent {
+            payment_id: expiring_id.clone(),
+            customer: customer.clone(),
+            recipient: sponsor.clone(),
+            amount: 40_000_000,
+            remaining: 60_000_000,
+        }
+        .to_xdr(&env, &contract_id)]
+    );
+    assert_eq!(token_client.balance(&sponsor), 940_000_000);
+    env.ledger().set_sequence_number(111);
+    client.refund(&expiring_id);
+    assert_eq!(
+        env.events().all().filter_by_contract(&contract_id),
+        [ExpiryEvent {
+            payment_id: expiring_id.clone(),
+            customer: customer.clone(),
+            recipient: sponsor.clone(),
+            amount: 60_000_000,
+        }
+        .to_xdr(&env, &contract_id)]
+    );
+    assert_eq!(token_client.balance(&sponsor), 1_000_000_000);
+    assert_eq!(token_client.balance(&customer), 1_000_000_000);
+    assert_eq!(token_client.balance(&merchant), 0);
+}
+
+#[test]
+fn test_deposit_sets_customer_as_payer() {
+    let (env, client, _contract_id, _admin, customer, merchant, _usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 100i128);
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.payer, customer);
+    assert_eq!(payment.refund_to, None);
+}
+
+#[test]
+fn test_payment_request_funded_on_merchant_terms() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.create_payment_request(&merchant, &payment_id, &120_000_000i128, &usdc, &40);
+    let request = client.get_payment_request(&payment_id);
+    assert_eq!(request.merchant, merchant);
+    assert_eq!(request.amount, 120_000_000);
+    assert_eq!(request.funding_deadline, 40);
+    assert_eq!(
+        client.try_deposit(&customer, &payment_id, &customer, &usdc, &1i128, &None),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
+
+    client.fund_request(&customer, &payment_id);
+    assert_eq!(
+        client.try_get_payment_request(&payment_id),
+        Err(Ok(EscrowError::PaymentRequestNotFound))
+    );
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment.merchant, merchant);
+    assert_eq!(payment.customer, customer);
+    assert_eq!(payment.amount, 120_000_000);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(
+        client.try_fund_request(&customer, &payment_id),
+        Err(Ok(EscrowError::PaymentRequestNotFound))
+    );
+
+    client.release(&admin, &payment_id);
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&merchant), 120_000_000);
+    assert_eq!(token_client.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_payment_request_expires_unfunded() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.create_payment_request(&merchant, &payment_id, &100i128, &usdc, &40);
+    assert_eq!(
+        client.try_create_payment_request(&merchant, &payment_id, &200i128, &usdc, &80),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
+
+    env.ledger().set_sequence_number(41);
+    assert_eq!(
+        client.try_fund_request(&customer, &payment_id),
+        Err(Ok(EscrowError::PaymentRequestExpired))
+    );
+
+    let other_merchant = Address::generate(&env);
+    assert_eq!(
+        client.try_create_payment_request(&other_merchant, &payment_id, &200i128, &usdc, &80),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
+    client.create_payment_request(&merchant, &payment_id, &200i128, &usdc, &80);
+    client.fund_request(&customer, &payment_id);
+    assert_eq!(client.get_payment(&payment_id).amount, 200);
+}
+
+#[test]
+fn test_expired_payment_request_releases_id() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+
+    client.create_payment_request(&merchant, &payment_id, &100i128, &usdc, &40);
+    assert_eq!(
+        client.try_deposit(&customer, &payment_id, &merchant, &usdc, &100i128, &None),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
+
+    env.ledger().set_sequence_number(41);
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 100i128);
+    assert_eq!(
+        client.try_get_payment_request(&payment_id),
+        Err(Ok(EscrowError::PaymentRequestNotFound))
+    );
+    assert_eq!(client.get_payment(&payment_id).amount, 100);
+}
+
+#[test]
+fn test_payment_request_validation() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let unlisted = register_token(&env, &customer);
+
+    assert_eq!(
+        client.try_create_payment_request(&merchant, &payment_id, &0i128, &usdc, &40),
+        Err(Ok(EscrowError::InvalidAmount))
+    );
+    assert_eq!(
+        client.try_create_payment_request(&merchant, &payment_id, &100i128, &unlisted, &40),
+        Err(Ok(EscrowError::TokenNotAllowed))
+    );
+    assert_eq!(
+        client.try_create_payment_request(&merchant, &payment_id, &100i128, &usdc, &10),
+        Err(Ok(EscrowError::InvalidFundingDeadline))
+    );
+
+    deposit_default_ttl(&client, &customer, &payment_id, &merchant, 100i128);
+    assert_eq!(
+        client.try_create_payment_request(&merchant, &payment_id, &100i128, &usdc, &40),
+        Err(Ok(EscrowError::PaymentAlreadyExists))
+    );
+
+    let request_id = make_id(&env, 2);
+    client.create_payment_request(&merchant, &request_id, &100i128, &usdc, &40);
+    client.pause(&admin, &PauseScope::Deposits);
+    assert_eq!(
+        client.try_fund_request(&customer, &request_id),
+        Err(Ok(EscrowError::Paused))
+    );
+    client.unpause(&admin, &PauseScope::Deposits);
+    client.fund_request(&customer, &request_id);
+}
+
+fn merchant_signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+fn sign_quote(
+    env: &Env,
+    contract_id: &Address,
+    signing_key: &SigningKey,
+    quote: &MerchantQuote,
+) -> BytesN<64> {
+    let message = (contract_id.clone(), quote.clone()).to_xdr(env);
+    let mut buf = [0u8; 512];
+    let len = message.len() as usize;
+    message.copy_into_slice(&mut buf[..len]);
+    BytesN::from_array(env, &signing_key.sign(&buf[..len]).to_bytes())
+}
+
+fn register_signing_key(
+    env: &Env,
+    client: &PaymentEscrowContractClient<'_>,
+    merchant: &Address,
+    signing_key: &SigningKey,
+) {
+    client.set_merchant_signing_key(
+        merchant,
+        &BytesN::from_array(env, &signing_key.verifying_key().to_bytes()),
+    );
+}
+
+fn quote_for(env: &Env, merchant: &Address, usdc: &Address, seed: u8, nonce: u64) -> MerchantQuote {
+    MerchantQuote {
+        payment_id: make_id(env, seed),
+        merchant: merchant.clone(),
+        amount: 75_000_000,
+        token: usdc.clone(),
+        expiry: 40,
+        nonce,
+        customer: None,
+    }
+}
+
+#[test]
+fn test_deposit_with_quote() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let signing_key = merchant_signing_key(7);
+    register_signing_key(&env, &client, &merchant, &signing_key);
+    assert_eq!(
+        client.get_merchant_signing_key(&merchant),
+        Some(BytesN::from_array(
+            &env,
+            &signing_key.verifying_key().to_bytes()
+        ))
+    );
+
+    let quote = MerchantQuote {
+        customer: Some(customer.clone()),
+        ..quote_for(&env, &merchant, &usdc, 1, 1)
+    };
+    let signature = sign_quote(&env, &contract_id, &signing_key, &quote);
+    let payment_id = client.deposit_with_quote(&customer, &quote, &signature);
+
+    let payment = client.get_payment(&payment_id);
+    assert_eq!(payment_id, quote.payment_id);
+    assert_eq!(payment.merchant, merchant);
+    assert_eq!(payment.customer, customer);
+    assert_eq!(payment.amount, 75_000_000);
+    assert!(client.is_quote_nonce_used(&merchant, &1));
+    assert!(!client.is_quote_nonce_used(&merchant, &2));
+
+    let replay = quote_for(&env, &merchant, &usdc, 2, 1);
+    let signature = sign_quote(&env, &contract_id, &signing_key, &replay);
+    assert_eq!(
+        client.try_deposit_with_quote(&customer, &replay, &signature),
+        Err(Ok(EscrowError::QuoteAlreadyUsed))
+    );
+
+    let token_client = token::Client::new(&env, &usdc);
+    assert_eq!(token_client.balance(&customer), 925_000_000);
+}
+
+#[test]
+fn test_deposit_with_quote_rejects_tampering() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let signing_key = merchant_signing_key(7);
+    register_signing_key(&env, &client, &merchant, &signing_key);
+
+    let quote = quote_for(&env, &merchant, &usdc, 1, 1);
+    let signature = sign_quote(&env, &contract_id, &signing_key, &quote);
+    let tampered = MerchantQuote {
+        amount: 1,
+        ..quote.clone()
+    };
+    assert!(client
+        .try_deposit_with_quote(&customer, &tampered, &signature)
+        .is_err());
+
+    let forged = sign_quote(&env, &contract_id, &merchant_signing_key(8), &quote);
+    assert!(client
+        .try_deposit_with_quote(&customer, &quote, &forged)
+        .is_err());
+
+    let other_contract = Address::generate(&env);
+    let foreign = sign_quote(&env, &other_contract, &signing_key, &quote);
+    assert!(client
+        .try_deposit_with_quote(&customer, &quote, &foreign)
+        .is_err());
+    assert!(!client.is_quote_nonce_used(&merchant, &1));
+}
+
+#[test]
+fn test_deposit_with_quote_validation() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let signing_key = merchant_signing_key(7);
+
+    let quote = quote_for(&env, &merchant, &usdc, 1, 1);
+    let signature = sign_quote(&env, &contract_id, &signing_key, &quote);
+    assert_eq!(
+        client.try_deposit_with_quote(&customer, &quote, &signature),
+        Err(Ok(EscrowError::MerchantKeyNotFound))
+    );
+
+    register_signing_key(&env, &client, &merchant, &signing_key);
+    env.ledger().set_sequence_number(41);
+    assert_eq!(
+        client.try_deposit_with_quote(&customer, &quote, &signature),
+        Err(Ok(EscrowError::QuoteExpired))
+    );
+
+    env.ledger().set_sequence_number(40);
+    let bound = MerchantQuote {
+        customer: Some(Address::generate(&env)),
+        ..quote.clone()
+    };
+    let bound_signature = sign_quote(&env, &contract_id, &signing_key, &bound);
+    assert_eq!(
+        client.try_deposit_with_quote(&customer, &bound, &bound_signature),
+        Err(Ok(EscrowError::QuoteCustomerMismatch))
+    );
+    assert!(!client.is_quote_nonce_used(&merchant, &1));
+
+    client.clear_merchant_signing_key(&merchant);
+    assert_eq!(client.get_merchant_signing_key(&merchant), None);
+    assert_eq!(
+        client.try_deposit_with_quote(&customer, &quote, &signature),
+        Err(Ok(EscrowError::MerchantKeyNotFound))
+    );
+}
+
+fn metadata_options(
+    env: &Env,
+    external_ref: Option<&str>,
+    memo: Option<&str>,
+) -> Option<DepositOptions> {
+    Some(DepositOptions {
+        external_ref: external_ref.map(|external_ref| String::from_str(env, external_ref)),
+        order_hash: Some(BytesN::from_array(env, &[9; 32])),
+        memo: memo.map(|memo| String::from_str(env, memo)),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_payment_metadata_and_reference_lookup() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let reference = "pay_3f8a1c2e-6b7d-4e9f-a0b1-c2d3e4f5a6b7";
+
+    client.deposit(
+        &customer,
+        &payment_id,
+        &merchant,
+        &usdc,
+        &100_000_000i128,
+        &metadata_options(&env, Some(reference), Some("order #1042")),
+    );
+
+    let metadata = client.get_payment_metadata(&payment_id).unwrap();
+    assert_eq!(
+        metadata.external_ref,
+        Some(String::from_str(&env, reference))
+    );
+    assert_eq!(
+        metadata.order_hash,
+        Some(BytesN::from_array(&env, &[9; 32]))
+    );
+    assert_eq!(metadata.memo, Some(String::from_str(&env, "order #1042")));
+
+    let payment = client.get_payment_by_reference(&String::from_str(&env, reference));
+    assert_eq!(payment.payment_id, payment_id);
+    assert_eq!(
+        client
+            .try_get_payment_by_reference(&String::from_str(&env, "pay_unknown"))
+            .err(),
+        Some(Ok(EscrowError::PaymentNotFound))
+    );
+
+    assert_eq!(
+        client.try_deposit(
+            &customer,
+            &make_id(&env, 2),
+            &merchant,
+            &usdc,
+            &100_000_000i128,
+            &metadata_options(&env, Some(reference), None),
+        ),
+        Err(Ok(EscrowError::ReferenceAlreadyUsed))
+    );
+
+    let plain_id = make_id(&env, 3);
+    deposit_default_ttl(&client, &customer, &plain_id, &merchant, 100i128);
+    assert_eq!(client.get_payment_metadata(&plain_id), None);
+
+    let hash_only_id = make_id(&env, 4);
+    client.deposit(
+        &customer,
+        &hash_only_id,
+        &merchant,
+        &usdc,
+        &100i128,
+        &metadata_options(&env, None, None),
+    );
+    assert_eq!(
+        client
+            .get_payment_metadata(&hash_only_id)
+            .unwrap()
+            .external_ref,
+        None
+    );
+}
+
+#[test]
+fn test_payment_metadata_validation() {
+    let (env, client, _contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let payment_id = make_id(&env, 1);
+    let long_reference = [b'r'; 65];
+    let long_memo = [b'm'; 257];
+
+    for options in [
+        metadata_options(&env, Some(""), None),
+        metadata_options(
+            &env,
+            Some(core::str::from_utf8(&long_reference).unwrap()),
+            None,
+        ),
+        metadata_options(&env, None, Some(core::str::from_utf8(&long_memo).unwrap())),
+    ] {
+        assert_eq!(
+            client.try_deposit(&customer, &payment_id, &merchant, &usdc, &100i128, &options),
+            Err(Ok(EscrowError::InvalidMetadata))
+        );
+    }
+
+    client.deposit(
+        &customer,
+        &payment_id,
+        &merchant,
+        &usdc,
+        &100i128,
+        &metadata_options(
+            &env,
+            Some(core::str::from_utf8(&long_reference[..64]).unwrap()),
+            Some(core::str::from_utf8(&long_memo[..256]).unwrap()),
+        ),
+    );
+}
+
+fn subscription_terms(
+    merchant: &Address,
+    token: &Address,
+    amount_per_period: i128,
+    period_ledgers: u32,
+    max_periods: u32,
+) -> SubscriptionTerms {
+    SubscriptionTerms {
+        merchant: merchant.clone(),
+        token: token.clone(),
+        amount_per_period,
+        period_ledgers,
+        max_periods,
+    }
+}
+
+#[test]
+fn test_subscription_charges_each_period() {
+    let (env, client, contract_id, admin, customer, merchant, usdc) = setup_env();
+    let subscription_id = make_id(&env, 1);
+    let token_client = token::Client::new(&env, &usdc);
+
+    client.create_subscription(
+        &customer,
+        &subscription_id,
+        &subscription_terms(&merchant, &usdc, 100_000_000, 50, 3),
+    );
+    assert_eq!(token_client.allowance(&customer, &contract_id), 300_000_000);
+    let subscription = client.get_subscription(&subscription_id);
+    assert!(subscription.active);
+    assert_eq!(subscription.periods_charged, 0);
+    assert_eq!(subscription.next_charge_ledger, 10);
+
+    let first = client.charge_period(&subscription_id);
+    assert_eq!(
+        first,
+        client.get_subscription_payment_id(&subscription_id, &0)
+    );
+    let payment = client.get_payment(&first);
+    assert_eq!(payment.amount, 100_000_000);
+    assert_eq!(payment.customer, customer);
+    assert_eq!(payment.payer, customer);
+    assert_eq!(payment.merchant, merchant);
+    assert_eq!(payment.status, PaymentStatus::Pending);
+    assert_eq!(token_client.balance(&customer), 900_000_000);
+    assert_eq!(token_client.balance(&contract_id), 100_000_000);
+    assert_eq!(client.get_locked_total(&usdc), 100_000_000);
+
+    assert_eq!(
+        client.try_charge_period(&subscription_id),
+        Err(Ok(EscrowError::ChargeNotDue))
+    );
+
+    env.ledger().set_sequence_number(60);
+    let second = client.charge_period(&subscription_id);
+    assert_ne!(second, first);
+    env.ledger().set_sequence_number(110);
+    client.charge_period(&subscription_id);
+
+    let subscription = client.get_subscription(&subscription_id);
+    assert_eq!(subscription.periods_charged, 3);
+    assert_eq!(subscription.next_charge_ledger, 160);
+    assert_eq!(token_client.allowance(&customer, &contract_id), 0);
+    assert_eq!(token_client.balance(&customer), 700_000_000);
+
+    env.ledger().set_sequence_number(160);
+    assert_eq!(
+        client.try_charge_period(&subscription_id),
+        Err(Ok(EscrowError::SubscriptionComplete))
+    );
+
+    client.release(&admin, &second);
+    assert_eq!(token_client.balance(&merchant), 100_000_000);
+}
+
+#[test]
+fn test_cancel_subscription_revokes_allowance() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let subscription_id = make_id(&env, 1);
+    let token_client = token::Client::new(&env, &usdc);
+
+    client.create_subscription(
+        &customer,
+        &subscription_id,
+        &subscription_terms(&merchant, &usdc, 100_000_000, 50, 12),
+    );
+    let payment_id = client.charge_period(&subscription_id);
+
+    client.cancel_subscription(&subscription_id);
+    assert_eq!(env.auths()[0].0, customer);
+    assert_eq!(token_client.allowance(&customer, &contract_id), 0);
+    let subscription = client.get_subscription(&subscription_id);
+    assert!(!subscription.active);
+    assert_eq!(subscription.periods_charged, 1);
+
+    env.ledger().set_sequence_number(60);
+    assert_eq!(
+        client.try_charge_period(&subscription_id),
+        Err(Ok(EscrowError::SubscriptionInactive))
+    );
+    assert_eq!(
+        client.try_cancel_subscription(&subscription_id),
+        Err(Ok(EscrowError::SubscriptionInactive))
+    );
+
+    // Payments already charged stay in escrow.
+    assert_eq!(client.get_balance(&payment_id), 100_000_000);
+}
+
+#[test]
+fn test_subscriptions_share_customer_allowance() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let first_id = make_id(&env, 1);
+    let second_id = make_id(&env, 2);
+    let token_client = token::Client::new(&env, &usdc);
+
+    client.create_subscription(
+        &customer,
+        &first_id,
+        &subscription_terms(&merchant, &usdc, 100, 50, 3),
+    );
+    client.create_subscription(
+        &customer,
+        &second_id,
+        &subscription_terms(&merchant, &usdc, 50, 20, 4),
+    );
+    assert_eq!(token_client.allowance(&customer, &contract_id), 500);
+
+    client.charge_period(&first_id);
+    client.charge_period(&second_id);
+    assert_eq!(token_client.allowance(&customer, &contract_id), 350);
+
+    client.cancel_subscription(&second_id);
+    assert_eq!(token_client.allowance(&customer, &contract_id), 200);
+
+    env.ledger().set_sequence_number(60);
+    client.charge_period(&first_id);
+    env.ledger().set_sequence_number(110);
+    client.charge_period(&first_id);
+    assert_eq!(token_client.allowance(&customer, &contract_id), 0);
+    assert_eq!(client.get_subscription(&first_id).periods_charged, 3);
+}
+
+#[test]
+fn test_subscription_payment_ids_are_reserved() {
+    let (env, client, contract_id, _admin, customer, merchant, usdc) = setup_env();
+    let subscription_id = make_id(&env, 1);
+    let reserved = client.get_subscription_payment_id(&subscription_id, &0);
+    assert_eq!(&reserved.to_array()[..4], b"SUB:");
+
+    assert_eq!(
+        client.try_deposit(&customer, &reserved, &customer, &usdc, &1i128, &None),
+        Err(Ok(EscrowError::ReservedPaymentId))
+    );
+    assert_eq!(
+        client.try_deposit_for(&customer, &customer, &reserved, &customer, &usdc, &1i128, &None),
+        Err(Ok(EscrowError::ReservedPaymentId))
+    );
+    assert_eq!(
+        client.try_create_payment_request(&merchant, &reserved, &1i128, &usdc, &40),
+        Err(Ok(EscrowError::ReservedPaymentId))
+    );
+    let signing_key = merchant_signing_key(7);
+    register_signing_key(&env, &client, &merchant, &signing_key);
+    let quote = MerchantQuote {
+        payment_id: reserved.clone(),
+        ..quote_for(&env, &merchant, &usdc, 1, 1)
+    };
+    assert_eq!(
+        client.try_deposit_with_quote(
+            &customer,
+            &quote,
+            &sign_quote(&env, &contract_id, &signing_key, &quote)
+        ),
+        Err(Ok(EscrowError::ReservedPaymentId))
+    );
+
+    client.create_subscription(
+        &customer,
+        &subscription_id,
+        &subscription_terms(&merchant, &usdc, 100, 50, 3),
+    );
+    assert_eq!(client.charge_period(&subscription_id), reserved);
+}
+
+#[test]
+fn test_subscription_validation() {
+    let (env, client, _contract_id, admin, customer, merchant, usdc) = setup_env();
+    let subscription_id = make_id(&env, 1);
+    let unlisted = register_token(&env, &customer);
+
+    assert_eq!(
+        client.try_create_subscription(
+            &customer,
+            &subscription_id,
+            &subscription_terms(&merchant, &usdc, 0, 50, 3),
+        ),
+        Err(Ok(EscrowError::InvalidAmount))
+    );
+    assert_eq!(
+        client.try_create_subscription(
+            &customer,
+            &subscription_id,
+            &subscription_terms(&merchant, &unlisted, 100, 50, 3),
+        ),
+        Err(Ok(EscrowError::TokenNotAllowed))
+    );
+    for (period_ledgers, max_periods) in [(0, 3), (50, 0), (u32::MAX, 2)] {
+        assert_eq!(
+            client.try_create_subscription(
+                &customer,
+                &subscription_id,
+                &subscription_terms(&merchant, &usdc, 100, period_ledgers, max_periods),
+            ),
+            Err(Ok(EscrowError::InvalidSubscription))
+        );
+    }
+    assert_eq!(
+        client.try_get_subscription(&subscription_id).err(),
+        Some(Ok(EscrowError::SubscriptionNotFound))
+    );
+    assert_eq!(
+        client.try_charge_period(&subscription_id),
+        Err(Ok(EscrowError::SubscriptionNotFound))
+    );
+
+    client.create_subscription(
+        &customer,
+        &subscription_id,
+        &subscription_terms(&merchant, &usdc, 100, 50, 3),
+    );
+    assert_eq!(
+        client.try_create_subscription(
+            &customer,
+            &subscription_id,
+            &subscription_terms(&merchant, &usdc, 100, 50, 3),
+        ),
+        Err(Ok(EscrowError::SubscriptionAlreadyExists))
+    );
+
+    client.pause(&admin, &PauseScope::Deposits);
+    assert_eq!(
+        client.try_charge_period(&subscription_id),
+        Err(Ok(EscrowError::Paused))
     );
-    client.resolve_dispute(&admin, &payment_id, &merchant);
-    client.resolve_dispute(&admin, &payment_id, &merchant);
 }